@@ -0,0 +1,567 @@
+use {
+    crate::{
+        macors::{Event, KeyStroke, Macro, MouseEventButton, MouseEventMove, first_mouse_position},
+        migrate,
+    },
+    serde::{Serialize, de::DeserializeOwned},
+    std::{
+        collections::HashMap,
+        io::{self, Read},
+    },
+};
+
+// A compact encoding of macros, for recordings too large to parse quickly as text. After a
+// short header the events follow one after another as a tag byte and its fields. Integers are
+// LEB128 varints, mouse coordinates are zigzag varint deltas from the previous mouse event
+// whenever both are whole numbers, and keys and buttons are spelled out once and referred to
+// by index afterwards.
+const MAGIC: &[u8; 4] = b"MCRB";
+// version 1 lacked the macro's format version in the header
+const VERSION: u8 = 2;
+
+const KEY_PRESS: u8 = 0;
+const KEY_RELEASE: u8 = 1;
+const MOUSE_PRESS: u8 = 2;
+const MOUSE_RELEASE: u8 = 3;
+const MOUSE_MOVE: u8 = 4;
+const WAIT: u8 = 5;
+const SECRET: u8 = 6;
+const LABEL: u8 = 7;
+/// set on the tag of a mouse event whose coordinates are stored as raw floats
+const RAW_COORDS: u8 = 0x80;
+// the most events room is made for up front, lengths come from the file and can't be trusted
+const MAX_PREALLOC: usize = 1 << 16;
+
+/// Everything known about a binary macro before its events are read
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Header {
+    /// format version of the macro, as opposed to the version of the binary encoding
+    pub version: u32,
+    pub description: String,
+    pub ended_by: Option<String>,
+    /// number of events which follow
+    pub len: usize,
+    /// every secret placeholder in the events, so they can be resolved before playback
+    pub secrets: Vec<String>,
+    /// where the first mouse event happens, for playing relative to the pointer
+    pub origin: Option<(f64, f64)>,
+}
+
+/// Encode the macro
+pub fn encode(mcro: &Macro) -> Result<Vec<u8>, String> {
+    let mut secrets: Vec<String> = Vec::new();
+    for ev in &mcro.events {
+        if let Event::Secret { name } = ev
+            && !secrets.contains(name)
+        {
+            secrets.push(name.clone());
+        }
+    }
+
+    let mut w = Writer::default();
+    w.out.extend_from_slice(MAGIC);
+    w.out.push(VERSION);
+    w.varint(u64::from(mcro.version));
+    w.string(&mcro.description);
+    w.option(mcro.ended_by.as_deref());
+    w.varint(mcro.events.len() as u64);
+    w.varint(secrets.len() as u64);
+    for name in &secrets {
+        w.string(name);
+    }
+    match first_mouse_position(&mcro.events) {
+        Some((x, y)) => {
+            w.out.push(1);
+            w.float(x);
+            w.float(y);
+        }
+        None => w.out.push(0),
+    }
+    for ev in &mcro.events {
+        w.event(ev)?;
+    }
+    Ok(w.out)
+}
+
+/// Decode a whole macro
+pub fn decode(bytes: &[u8]) -> Result<Macro, String> {
+    let reader = Reader::new(bytes)?;
+    let Header {
+        version,
+        description,
+        ended_by,
+        len,
+        ..
+    } = reader.header().clone();
+    let mut events = Vec::with_capacity(len.min(MAX_PREALLOC));
+    for ev in reader {
+        events.push(ev?);
+    }
+    Ok(Macro {
+        version,
+        description,
+        ended_by,
+        events,
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    out: Vec<u8>,
+    last_pos: (f64, f64),
+    symbols: HashMap<String, u64>,
+}
+
+impl Writer {
+    fn event(&mut self, ev: &Event) -> Result<(), String> {
+        match ev {
+            Event::KeyPress(stroke) => {
+                self.out.push(KEY_PRESS);
+                self.symbol(&stroke.key)?;
+                self.option(stroke.text.as_deref());
+            }
+            Event::KeyRelease(key) => {
+                self.out.push(KEY_RELEASE);
+                self.symbol(key)?;
+            }
+            Event::MousePress(m) => {
+                self.coords(MOUSE_PRESS, m.x, m.y);
+                self.symbol(&m.button)?;
+            }
+            Event::MouseRelease(m) => {
+                self.coords(MOUSE_RELEASE, m.x, m.y);
+                self.symbol(&m.button)?;
+            }
+            Event::MouseMove(m) => self.coords(MOUSE_MOVE, m.x, m.y),
+            Event::Wait(ms) => {
+                self.out.push(WAIT);
+                self.varint(*ms);
+            }
+            Event::Secret { name } => {
+                self.out.push(SECRET);
+                self.string(name);
+            }
+            Event::Label { name } => {
+                self.out.push(LABEL);
+                self.string(name);
+            }
+        }
+        Ok(())
+    }
+
+    fn coords(&mut self, tag: u8, x: f64, y: f64) {
+        let (last_x, last_y) = self.last_pos;
+        self.last_pos = (x, y);
+        let delta = |v: f64, last: f64| whole(v)?.checked_sub(whole(last)?);
+        if let (Some(dx), Some(dy)) = (delta(x, last_x), delta(y, last_y)) {
+            self.out.push(tag);
+            self.varint(zigzag(dx));
+            self.varint(zigzag(dy));
+        } else {
+            self.out.push(tag | RAW_COORDS);
+            self.float(x);
+            self.float(y);
+        }
+    }
+
+    // keys and buttons in their serde form, the first time they come up
+    fn symbol(&mut self, value: &impl Serialize) -> Result<(), String> {
+        let name = serde_json::to_string(value).map_err(|e| e.to_string())?;
+        let next = self.symbols.len() as u64;
+        let index = *self.symbols.entry(name.clone()).or_insert(next);
+        self.varint(index);
+        if index == next {
+            self.string(&name);
+        }
+        Ok(())
+    }
+
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.out.push(v as u8);
+    }
+
+    fn float(&mut self, v: f64) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.varint(s.len() as u64);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn option(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => {
+                self.out.push(1);
+                self.string(s);
+            }
+            None => self.out.push(0),
+        }
+    }
+}
+
+/// Reads the events of a binary macro one at a time
+pub struct Reader<R> {
+    input: R,
+    header: Header,
+    remaining: usize,
+    last_pos: (f64, f64),
+    symbols: Vec<String>,
+}
+
+impl<R: Read> Reader<R> {
+    /// Read the header, leaving the events to be iterated
+    pub fn new(mut input: R) -> Result<Self, String> {
+        let mut magic = [0u8; 4];
+        input
+            .read_exact(&mut magic)
+            .map_err(|_| "not a binary macro".to_string())?;
+        if &magic != MAGIC {
+            return Err("not a binary macro".to_string());
+        }
+        let mut reader = Reader {
+            input,
+            header: Header::default(),
+            remaining: 0,
+            last_pos: (0.0, 0.0),
+            symbols: Vec::new(),
+        };
+        let encoding = reader.byte()?;
+        if !(1..=VERSION).contains(&encoding) {
+            return Err(format!("unsupported binary macro version {encoding}"));
+        }
+        // macros written before they were versioned are version 0
+        let version = if encoding >= 2 { reader.varint()? } else { 0 };
+        reader.header.version = u32::try_from(version).unwrap_or(u32::MAX);
+        migrate::check(reader.header.version)?;
+        reader.header.description = reader.string()?;
+        reader.header.ended_by = reader.option()?;
+        reader.header.len = reader.varint()? as usize;
+        let secrets = reader.varint()?;
+        for _ in 0..secrets {
+            let name = reader.string()?;
+            reader.header.secrets.push(name);
+        }
+        if reader.byte()? != 0 {
+            reader.header.origin = Some((reader.float()?, reader.float()?));
+        }
+        reader.remaining = reader.header.len;
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn event(&mut self) -> Result<Event, String> {
+        let tag = self.byte()?;
+        let ev = match tag & !RAW_COORDS {
+            KEY_PRESS => Event::KeyPress(KeyStroke {
+                key: self.symbol()?,
+                text: self.option()?,
+            }),
+            KEY_RELEASE => Event::KeyRelease(self.symbol()?),
+            MOUSE_PRESS | MOUSE_RELEASE => {
+                let (x, y) = self.coords(tag)?;
+                let m = MouseEventButton {
+                    x,
+                    y,
+                    button: self.symbol()?,
+                };
+                if tag & !RAW_COORDS == MOUSE_PRESS {
+                    Event::MousePress(m)
+                } else {
+                    Event::MouseRelease(m)
+                }
+            }
+            MOUSE_MOVE => {
+                let (x, y) = self.coords(tag)?;
+                Event::MouseMove(MouseEventMove { x, y })
+            }
+            WAIT => Event::Wait(self.varint()?),
+            SECRET => Event::Secret {
+                name: self.string()?,
+            },
+            LABEL => Event::Label {
+                name: self.string()?,
+            },
+            _ => return Err(format!("unknown event tag {tag:#x}")),
+        };
+        Ok(ev)
+    }
+
+    fn coords(&mut self, tag: u8) -> Result<(f64, f64), String> {
+        let pos = if tag & RAW_COORDS != 0 {
+            (self.float()?, self.float()?)
+        } else {
+            let (last_x, last_y) = self.last_pos;
+            let (dx, dy) = (unzigzag(self.varint()?), unzigzag(self.varint()?));
+            let apply = |last: f64, d: i64| {
+                let v = whole(last)?.checked_add(d)?;
+                Some(v as f64)
+            };
+            apply(last_x, dx)
+                .zip(apply(last_y, dy))
+                .ok_or("corrupt mouse coordinates")?
+        };
+        self.last_pos = pos;
+        Ok(pos)
+    }
+
+    fn symbol<T: DeserializeOwned>(&mut self) -> Result<T, String> {
+        let index = self.varint()? as usize;
+        if index == self.symbols.len() {
+            let name = self.string()?;
+            self.symbols.push(name);
+        }
+        let name = self
+            .symbols
+            .get(index)
+            .ok_or_else(|| format!("unknown symbol {index}"))?;
+        serde_json::from_str(name).map_err(|e| format!("unknown key or button {name}: {e}"))
+    }
+
+    fn bytes(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.input.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => "binary macro is cut short".to_string(),
+            _ => format!("Failed to read binary macro: {e}"),
+        })
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let mut buf = [0u8; 1];
+        self.bytes(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            v |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err("varint too long".to_string())
+    }
+
+    fn float(&mut self) -> Result<f64, String> {
+        let mut buf = [0u8; 8];
+        self.bytes(&mut buf)?;
+        Ok(f64::from_le_bytes(buf))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.varint()?;
+        let mut buf = Vec::new();
+        (&mut self.input)
+            .take(len)
+            .read_to_end(&mut buf)
+            .map_err(|e| format!("Failed to read binary macro: {e}"))?;
+        if buf.len() as u64 != len {
+            return Err("binary macro is cut short".to_string());
+        }
+        String::from_utf8(buf).map_err(|e| format!("invalid text in binary macro: {e}"))
+    }
+
+    fn option(&mut self) -> Result<Option<String>, String> {
+        match self.byte()? {
+            0 => Ok(None),
+            _ => self.string().map(Some),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Event, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let ev = self.event();
+        if ev.is_err() {
+            // nothing after a broken event can be trusted
+            self.remaining = 0;
+        }
+        Some(ev)
+    }
+}
+
+// the value as an integer, if it is exactly one (so -0.0 isn't)
+fn whole(v: f64) -> Option<i64> {
+    let i = v as i64;
+    ((i as f64).to_bits() == v.to_bits()).then_some(i)
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::storage::Format,
+        rdevin::{Button, Key},
+    };
+
+    fn mouse(x: f64, y: f64, button: Button) -> MouseEventButton {
+        MouseEventButton { x, y, button }
+    }
+
+    fn sample() -> Macro {
+        Macro {
+            description: "every kind of event".to_string(),
+            ended_by: Some("max event count of 100".to_string()),
+            events: vec![
+                Event::Wait(250),
+                Event::MouseMove(MouseEventMove { x: 10.0, y: 20.0 }),
+                Event::MousePress(mouse(-15.0, 3.0, Button::Left)),
+                Event::MouseRelease(mouse(-15.0, 3.0, Button::Left)),
+                Event::MouseMove(MouseEventMove { x: 10.5, y: -0.25 }),
+                Event::MousePress(mouse(1e15, -1e15, Button::Unknown(9))),
+                Event::MouseRelease(mouse(1e15, -1e15, Button::Unknown(9))),
+                Event::KeyPress(KeyStroke {
+                    key: Key::KeyA,
+                    text: Some("a".to_string()),
+                }),
+                Event::KeyRelease(Key::KeyA),
+                Event::KeyPress(KeyStroke {
+                    key: Key::Unknown(1234),
+                    text: None,
+                }),
+                Event::KeyRelease(Key::Unknown(1234)),
+                Event::Secret {
+                    name: "secret-1".to_string(),
+                },
+                Event::Label {
+                    name: "label-1".to_string(),
+                },
+                Event::Secret {
+                    name: "secret-1".to_string(),
+                },
+                Event::Wait(u64::MAX),
+            ],
+            ..Macro::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mcro = sample();
+        let decoded = decode(&encode(&mcro).unwrap()).unwrap();
+        assert_eq!(decoded, mcro);
+    }
+
+    #[test]
+    fn header() {
+        let mcro = sample();
+        let bytes = encode(&mcro).unwrap();
+        let reader = Reader::new(bytes.as_slice()).unwrap();
+        let header = reader.header();
+        assert_eq!(header.len, mcro.events.len());
+        assert_eq!(header.secrets, ["secret-1"]);
+        assert_eq!(header.origin, Some((10.0, 20.0)));
+        assert_eq!(header.ended_by, mcro.ended_by);
+    }
+
+    #[test]
+    fn odd_coordinates_keep_their_bits() {
+        let coords = [
+            (f64::NAN, 1.0),
+            (-0.0, 0.0),
+            (0.1, -7.75),
+            (f64::INFINITY, f64::NEG_INFINITY),
+            (i64::MAX as f64, i64::MIN as f64),
+            (-3.0, -4.0),
+        ];
+        let mcro = Macro {
+            events: coords
+                .iter()
+                .map(|&(x, y)| Event::MouseMove(MouseEventMove { x, y }))
+                .collect(),
+            ..Macro::default()
+        };
+        let decoded = decode(&encode(&mcro).unwrap()).unwrap();
+        for (ev, (x, y)) in decoded.events.iter().zip(coords) {
+            let Event::MouseMove(m) = ev else {
+                panic!("expected a mouse move, got {ev:?}");
+            };
+            assert_eq!(m.x.to_bits(), x.to_bits());
+            assert_eq!(m.y.to_bits(), y.to_bits());
+        }
+    }
+
+    #[test]
+    fn same_as_toml_and_json() {
+        let mut mcro = sample();
+        // neither can hold it
+        mcro.events.pop();
+        let binary = encode(&mcro).unwrap();
+        for format in [Format::Toml, Format::Json] {
+            let text = format.serialize(&decode(&binary).unwrap()).unwrap();
+            let back = format.deserialize(&text).unwrap();
+            assert_eq!(back, mcro);
+            assert_eq!(encode(&back).unwrap(), binary, "{format}");
+        }
+    }
+
+    #[test]
+    fn reads_version_1_files() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(1);
+        // description, no ended_by, two events, no secrets, origin at 5,6
+        bytes.extend_from_slice(&[4, b'o', b'l', b'd', b'!', 0, 2, 0, 1]);
+        bytes.extend_from_slice(&5.0f64.to_le_bytes());
+        bytes.extend_from_slice(&6.0f64.to_le_bytes());
+        // a wait, then a mouse move 5,6 away from the start
+        bytes.extend_from_slice(&[WAIT, 100, MOUSE_MOVE, 10, 12]);
+
+        let mcro = decode(&bytes).unwrap();
+        assert_eq!(mcro.version, 0);
+        assert_eq!(mcro.description, "old!");
+        assert_eq!(
+            mcro.events,
+            [
+                Event::Wait(100),
+                Event::MouseMove(MouseEventMove { x: 5.0, y: 6.0 })
+            ]
+        );
+        let migrated = Format::Binary.deserialize(&bytes).unwrap();
+        assert_eq!(migrated.version, migrate::CURRENT_VERSION);
+        assert_eq!(migrated.events, mcro.events);
+    }
+
+    #[test]
+    fn corrupt_input_is_an_error() {
+        let bytes = encode(&sample()).unwrap();
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "cut at {len}");
+        }
+        // a description claiming to be enormous
+        let mut huge = MAGIC.to_vec();
+        huge.push(VERSION);
+        huge.push(1);
+        huge.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+        assert!(decode(&huge).is_err());
+        // as does the event count
+        let mut many = encode(&Macro::default()).unwrap();
+        let len_at = many.len() - 3;
+        many.splice(
+            len_at..len_at + 1,
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+        );
+        assert!(decode(&many).is_err());
+    }
+}
@@ -1,21 +1,56 @@
 use {
+    crate::{schema::KeyName, storage::Format},
     anyhow::{Context, Error},
     config::Config as ConfigRs,
+    schemars::JsonSchema,
     serde::{Deserialize, Serialize},
     std::{
         fs::{self, File},
         io::Write,
         path::PathBuf,
+        str::FromStr,
     },
 };
 
 /// Config for macors
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
     #[serde(default = "default_stop_keystrokes")]
     /// The keystrokes which, when pressed, stop macro recording or playback.
+    #[schemars(with = "Vec<KeyName>")]
     pub stop_keystrokes: Vec<rdevin::Key>,
 
+    /// Further key sequences or chords which end a recording, each with its own outcome.
+    #[serde(default)]
+    pub terminators: Vec<Terminator>,
+
+    #[serde(default)]
+    /// The keystrokes which pause a recording or playback, none by default.
+    #[schemars(with = "Vec<KeyName>")]
+    pub pause_keystrokes: Vec<rdevin::Key>,
+
+    #[serde(default)]
+    /// The keystrokes which resume a paused recording or playback, none by default.
+    #[schemars(with = "Vec<KeyName>")]
+    pub resume_keystrokes: Vec<rdevin::Key>,
+
+    #[serde(default)]
+    /// The keystrokes which start playback of an armed `run --arm`, none by default.
+    #[schemars(with = "Vec<KeyName>")]
+    pub arm_keystrokes: Vec<rdevin::Key>,
+
+    #[serde(default)]
+    /// The keystrokes which drop a label into the recording, e.g. to split it there later. None
+    /// by default.
+    #[schemars(with = "Vec<KeyName>")]
+    pub label_keystrokes: Vec<rdevin::Key>,
+
+    #[serde(default)]
+    /// The keystrokes which toggle private mode while recording, typing in private mode is
+    /// replaced by a secret filled in at playback. None by default.
+    #[schemars(with = "Vec<KeyName>")]
+    pub private_keystrokes: Vec<rdevin::Key>,
+
     #[serde(default)]
     pub wait_strategy: WaitStrategy,
 
@@ -28,22 +63,79 @@ pub struct Config {
     #[serde(default = "default_record_non_drag_mouse_moves")]
     pub record_non_drag_mouse_moves: bool,
 
+    /// record at most one mouse move per this many milliseconds, 0 records every move
+    #[serde(default)]
+    pub mouse_move_sample_ms: u64,
+
     /// Initial wait time is never actually recorded, all event recordings only on the first
     /// true mouse/keyboard event. This is to take the pressure off.
     /// This value will be added to the very beginning of each recording to ensure that the
     /// recording can start with a delay.
     #[serde(default = "default_recording_initial_wait_ms")]
     pub recording_initial_wait_ms: u64,
+
+    /// The format new recordings are saved in, existing macros keep theirs.
+    #[serde(default)]
+    pub default_format: Format,
+
+    /// Input which is left out of recordings.
+    #[serde(default)]
+    pub record_filter: RecordFilter,
+
+    /// Limits after which a recording stops and saves on its own.
+    #[serde(default)]
+    pub record_limits: RecordLimits,
+
+    /// Play key presses by the character they typed while recording rather than by physical
+    /// key, so macros recorded on one keyboard layout work on another.
+    #[serde(default)]
+    pub type_by_character: bool,
+
+    /// Synthesized pointer movement leading up to each mouse press during playback.
+    #[serde(default)]
+    pub mouse_path: MousePath,
+
+    /// Conditions under which a running playback is aborted immediately.
+    #[serde(default)]
+    pub failsafe: Failsafe,
+
+    /// Named key remapping tables, e.g. for playing Linux macros on macOS.
+    #[serde(default)]
+    pub remaps: Vec<Remap>,
+
+    /// The remap table applied to every playback, if any.
+    #[serde(default)]
+    pub remap: Option<String>,
+
+    /// Key sequences which play a macro while `macors daemon` is running.
+    #[serde(default)]
+    pub bindings: Vec<Binding>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             stop_keystrokes: vec![rdevin::Key::Escape, rdevin::Key::Escape, rdevin::Key::Escape],
+            terminators: Vec::new(),
+            pause_keystrokes: Vec::new(),
+            resume_keystrokes: Vec::new(),
+            arm_keystrokes: Vec::new(),
+            label_keystrokes: Vec::new(),
+            private_keystrokes: Vec::new(),
             wait_strategy: WaitStrategy::ConstantMS(100),
             countdown_seconds: 3,
             record_non_drag_mouse_moves: false,
+            mouse_move_sample_ms: 0,
             recording_initial_wait_ms: 100,
+            default_format: Format::default(),
+            record_filter: RecordFilter::default(),
+            record_limits: RecordLimits::default(),
+            type_by_character: false,
+            mouse_path: MousePath::default(),
+            failsafe: Failsafe::default(),
+            remaps: Vec::new(),
+            remap: None,
+            bindings: Vec::new(),
         }
     }
 }
@@ -61,7 +153,313 @@ fn default_recording_initial_wait_ms() -> u64 {
     100
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Ends a recording when its keys are pressed in sequence, or its chord is held down all at
+/// once (in any order).
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Terminator {
+    pub action: StopAction,
+
+    #[serde(default)]
+    #[schemars(with = "Vec<KeyName>")]
+    pub keys: Vec<rdevin::Key>,
+
+    #[serde(default)]
+    #[schemars(with = "Vec<KeyName>")]
+    pub chord: Vec<rdevin::Key>,
+}
+
+/// What happens to a recording when it is ended
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StopAction {
+    Save,
+    /// throw the recording away
+    Discard,
+    /// save and play it back straight away
+    SaveAndReplay,
+    /// save and keep recording into a new macro named <name>-2, <name>-3, ...
+    SaveAndContinue,
+}
+
+/// Filter rules applied while recording. Filtered events leave no gap, the wait before the
+/// next recorded event spans them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct RecordFilter {
+    /// Keys which are never recorded, e.g. a push-to-talk key
+    #[serde(default)]
+    #[schemars(with = "Vec<KeyName>")]
+    pub ignore_keys: Vec<rdevin::Key>,
+
+    /// Leave out mouse events inside this rectangle
+    #[serde(default)]
+    pub ignore_inside: Option<Rect>,
+
+    /// Leave out mouse events outside this rectangle
+    #[serde(default)]
+    pub ignore_outside: Option<Rect>,
+
+    /// Only record the keyboard
+    #[serde(default)]
+    pub keyboard_only: bool,
+
+    /// Only record the mouse
+    #[serde(default)]
+    pub mouse_only: bool,
+}
+
+impl RecordFilter {
+    pub fn allows_key(&self, key: &rdevin::Key) -> bool {
+        !self.mouse_only && !self.ignore_keys.contains(key)
+    }
+
+    pub fn allows_point(&self, x: f64, y: f64) -> bool {
+        !self.ignore_inside.is_some_and(|r| r.contains(x, y))
+            && self.ignore_outside.is_none_or(|r| r.contains(x, y))
+    }
+}
+
+/// Guardrails for unattended recordings, a recording reaching any of them is saved as if the
+/// stop keystrokes were pressed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct RecordLimits {
+    /// Longest a recording may run, in seconds
+    #[serde(default)]
+    pub max_duration_secs: Option<u64>,
+
+    /// Most events (not counting waits) a recording may hold
+    #[serde(default)]
+    pub max_events: Option<usize>,
+
+    /// Stop after this many seconds without any input
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+/// A screen rectangle, written as "x,y,width,height" on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Rect {
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+impl FromStr for Rect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nums = s
+            .split(',')
+            .map(|n| n.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid rectangle {s:?}: {e}"))?;
+        let [x, y, width, height] = nums[..] else {
+            return Err(format!(
+                "invalid rectangle {s:?}, expected x,y,width,height"
+            ));
+        };
+        Ok(Rect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// Parse a key by its name in settings and macro files, e.g. "KeyA" or "ShiftLeft"
+pub fn parse_key(name: &str) -> Result<rdevin::Key, String> {
+    toml::Value::String(name.to_string())
+        .try_into()
+        .map_err(|_| format!("unknown key {name:?}"))
+}
+
+/// Intermediate mouse moves played from the pointer to each press target, for apps which only
+/// react to hovering.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MousePath {
+    #[serde(default)]
+    pub curve: Curve,
+
+    /// How long the pointer takes to reach the press target.
+    #[serde(default = "default_mouse_path_duration_ms")]
+    pub duration_ms: u64,
+
+    /// Number of moves making up the path.
+    #[serde(default = "default_mouse_path_steps")]
+    pub steps: u32,
+
+    /// Seed for the randomness of bezier curves, the same seed always plays the same paths.
+    /// Random when unset.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+impl Default for MousePath {
+    fn default() -> Self {
+        MousePath {
+            curve: Curve::default(),
+            duration_ms: default_mouse_path_duration_ms(),
+            steps: default_mouse_path_steps(),
+            seed: None,
+        }
+    }
+}
+
+fn default_mouse_path_duration_ms() -> u64 {
+    150
+}
+fn default_mouse_path_steps() -> u32 {
+    20
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    /// jump straight to the target, as recorded
+    #[default]
+    None,
+    /// a straight line at constant speed
+    Linear,
+    /// a straight line, speeding up and slowing down
+    Eased,
+    /// a randomly bent, eased curve
+    Bezier,
+}
+
+/// Playback failsafe, all held keys and buttons are released when it trips.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Failsafe {
+    #[serde(default = "default_failsafe_enabled")]
+    pub enabled: bool,
+
+    /// Abort when the real pointer enters one of these screen corners.
+    #[serde(default = "default_failsafe_corners")]
+    pub corners: Vec<Corner>,
+
+    /// Size in pixels of the square hot zone in each corner.
+    #[serde(default = "default_failsafe_corner_size_px")]
+    pub corner_size_px: f64,
+
+    /// Abort when the real pointer is found further than this many pixels away from where
+    /// playback last placed it (someone grabbed the mouse). 0 disables the check.
+    #[serde(default = "default_failsafe_mouse_moved_px")]
+    pub mouse_moved_px: f64,
+}
+
+impl Default for Failsafe {
+    fn default() -> Self {
+        Failsafe {
+            enabled: default_failsafe_enabled(),
+            corners: default_failsafe_corners(),
+            corner_size_px: default_failsafe_corner_size_px(),
+            mouse_moved_px: default_failsafe_mouse_moved_px(),
+        }
+    }
+}
+
+fn default_failsafe_enabled() -> bool {
+    true
+}
+fn default_failsafe_corners() -> Vec<Corner> {
+    vec![
+        Corner::TopLeft,
+        Corner::TopRight,
+        Corner::BottomLeft,
+        Corner::BottomRight,
+    ]
+}
+fn default_failsafe_corner_size_px() -> f64 {
+    5.0
+}
+fn default_failsafe_mouse_moved_px() -> f64 {
+    30.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    /// whether (x, y) lies within `size` pixels of this corner of a `width` x `height` screen
+    pub fn contains(self, x: f64, y: f64, width: f64, height: f64, size: f64) -> bool {
+        let near_left = x <= size;
+        let near_top = y <= size;
+        let near_right = x >= width - 1.0 - size;
+        let near_bottom = y >= height - 1.0 - size;
+        match self {
+            Corner::TopLeft => near_top && near_left,
+            Corner::TopRight => near_top && near_right,
+            Corner::BottomLeft => near_bottom && near_left,
+            Corner::BottomRight => near_bottom && near_right,
+        }
+    }
+}
+
+/// A named table of key replacements, applied to key presses and releases
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Remap {
+    pub name: String,
+
+    #[serde(default)]
+    pub keys: Vec<KeyMapping>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct KeyMapping {
+    #[schemars(with = "KeyName")]
+    pub from: rdevin::Key,
+    #[schemars(with = "KeyName")]
+    pub to: rdevin::Key,
+}
+
+impl Remap {
+    pub fn apply(&self, key: rdevin::Key) -> rdevin::Key {
+        self.keys
+            .iter()
+            .find(|m| m.from == key)
+            .map_or(key, |m| m.to)
+    }
+}
+
+/// A hotkey binding for `macors daemon`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Binding {
+    /// The keystrokes which, when pressed in sequence, play the macro.
+    #[schemars(with = "Vec<KeyName>")]
+    pub keys: Vec<rdevin::Key>,
+
+    /// Name of the macro to play
+    #[serde(rename = "macro")]
+    pub macro_name: String,
+
+    /// Number of times to repeat the macro
+    #[serde(default = "default_binding_repeat")]
+    pub repeat: usize,
+
+    /// Play relative to the current mouse position, like `run-offset`
+    #[serde(default)]
+    pub offset: bool,
+}
+
+fn default_binding_repeat() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum WaitStrategy {
     /// record actual time between events and put that in there
@@ -81,17 +479,49 @@ pub fn macors_path() -> PathBuf {
     home.join(".config/macors")
 }
 
+pub fn settings_path() -> PathBuf {
+    macors_path().join("settings.toml")
+}
+
+pub fn expansions_path() -> PathBuf {
+    macors_path().join("expansions.toml")
+}
+
+pub fn secrets_path() -> PathBuf {
+    macors_path().join("secrets.toml")
+}
+
 pub fn macros_path() -> PathBuf {
     let p = macors_path();
     p.join("macros")
 }
 
 impl Config {
+    /// The stop keystrokes (which save) followed by the configured terminators
+    pub fn terminators(&self) -> Vec<Terminator> {
+        let stop = Terminator {
+            action: StopAction::Save,
+            keys: self.stop_keystrokes.clone(),
+            chord: Vec::new(),
+        };
+        let mut terminators = vec![stop];
+        terminators.extend(self.terminators.iter().cloned());
+        terminators
+    }
+
+    /// The remap table with the given name
+    pub fn remap_profile(&self, name: &str) -> Result<&Remap, String> {
+        self.remaps
+            .iter()
+            .find(|r| r.name == name)
+            .ok_or_else(|| format!("no remap table named \"{name}\" in the settings"))
+    }
+
     pub fn load() -> Result<Self, Error> {
         // check for .config/macors/ folder and create it if it doesn't exist
         let config_dir = macors_path();
         fs::create_dir_all(&config_dir).expect("Failed to create config directory");
-        let settings_path = config_dir.join("settings.toml");
+        let settings_path = settings_path();
 
         // create the settings.toml file if it doesn't exist
         if !settings_path.exists() {
@@ -0,0 +1,145 @@
+use {
+    crate::{
+        config::{self, Binding, Config},
+        instance::InstanceLock,
+        listener::{self, KeySequence},
+        macors::{start_playback, start_playback_with_offset},
+    },
+    rdevin::EventType,
+    std::{
+        fs,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+            mpsc::RecvTimeoutError,
+        },
+        thread,
+        time::{Duration, Instant, SystemTime},
+    },
+};
+
+// how often settings.toml is checked for changes
+const RELOAD_POLL: Duration = Duration::from_secs(1);
+
+/// Print the bindings `macors daemon` would listen for
+pub fn list_bindings(cfg: &Config) {
+    if cfg.bindings.is_empty() {
+        println!(
+            "no bindings, add [[bindings]] to {}",
+            config::settings_path().display()
+        );
+        return;
+    }
+    for binding in &cfg.bindings {
+        let keys = listener::describe_keys(&binding.keys);
+        print!("{keys:<27} - {}", binding.macro_name);
+        if binding.repeat != 1 {
+            print!(" x{}", binding.repeat);
+        }
+        if binding.offset {
+            print!(" (offset)");
+        }
+        println!();
+    }
+}
+
+/// Listen globally and play the bound macro whenever one of the bindings' key sequences is
+/// pressed. Bindings are reloaded when settings.toml changes. Runs until interrupted.
+pub fn run(cfg: Config) {
+    let mut cfg = Arc::new(cfg);
+    let mut settings_modified = modified_time();
+    let mut last_reload_check = Instant::now();
+    list_bindings(&cfg);
+
+    let rx = listener::subscribe();
+    let playing = Arc::new(AtomicBool::new(false));
+    let mut recent_keys = KeySequence::default();
+    let mut was_playing = false;
+    // when the last playback was seen to have finished
+    let mut played_until = None;
+    loop {
+        let received = rx.recv_timeout(RELOAD_POLL);
+        if was_playing && !playing.load(Ordering::SeqCst) {
+            // the keys the playback pressed are no sequence the user typed
+            was_playing = false;
+            played_until = Some(SystemTime::now());
+            recent_keys.clear();
+        }
+        match received {
+            // the playback's own input, still on its way through the hook when it finished
+            Ok(event) if played_until.is_some_and(|until| event.time <= until) => {}
+            Ok(event) => match event.event_type {
+                EventType::KeyPress(key) => {
+                    recent_keys.push(key);
+                    let bound = cfg.bindings.iter().find(|b| recent_keys.ends_with(&b.keys));
+                    if let Some(binding) = bound {
+                        recent_keys.clear();
+                        if playing.load(Ordering::SeqCst) {
+                            // also swallows sequences typed by our own playback
+                            println!("playback in progress, ignoring {}", binding.macro_name);
+                        } else {
+                            spawn_playback(cfg.clone(), binding.clone(), playing.clone());
+                            was_playing = true;
+                        }
+                    }
+                }
+                EventType::ButtonPress(_) | EventType::ButtonRelease(_) => {
+                    recent_keys.clear();
+                }
+                _ => {}
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if last_reload_check.elapsed() >= RELOAD_POLL {
+            last_reload_check = Instant::now();
+            let modified = modified_time();
+            if modified != settings_modified {
+                settings_modified = modified;
+                match Config::load() {
+                    Ok(new_cfg) => {
+                        println!("settings changed, reloaded bindings:");
+                        list_bindings(&new_cfg);
+                        cfg = Arc::new(new_cfg);
+                    }
+                    Err(e) => eprintln!("Failed to reload settings, keeping old bindings: {e}"),
+                }
+            }
+        }
+    }
+}
+
+fn spawn_playback(cfg: Arc<Config>, binding: Binding, playing: Arc<AtomicBool>) {
+    playing.store(true, Ordering::SeqCst);
+    thread::spawn(move || {
+        let command = format!("daemon: {}", binding.macro_name);
+        let _lock = match InstanceLock::acquire(&command, false) {
+            Ok(lock) => lock,
+            Err(e) => {
+                eprintln!("{e}");
+                playing.store(false, Ordering::SeqCst);
+                return;
+            }
+        };
+        println!("playing {}", binding.macro_name);
+        for _ in 0..binding.repeat {
+            let res = if binding.offset {
+                start_playback_with_offset(&cfg, &binding.macro_name)
+            } else {
+                start_playback(&cfg, &binding.macro_name)
+            };
+            if let Err(abort) = res {
+                eprintln!("playback aborted, {abort}");
+                break;
+            }
+        }
+        playing.store(false, Ordering::SeqCst);
+    });
+}
+
+fn modified_time() -> Option<SystemTime> {
+    fs::metadata(config::settings_path())
+        .and_then(|m| m.modified())
+        .ok()
+}
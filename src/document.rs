@@ -0,0 +1,337 @@
+use {
+    crate::{
+        macors::{Event, Macro},
+        storage::{self, Format},
+    },
+    toml_edit::{ArrayOfTables, DocumentMut, InlineTable, Item, Table, Value},
+};
+
+// the most old × new event pairs compared when lining up a changed stretch of events, past
+// that they are paired up in order
+const MAX_DIFF_CELLS: usize = 1 << 22;
+
+/// Rewrite a macro's TOML document to hold `mcro`, touching only what changed so the comments,
+/// ordering and spacing of everything else survive
+pub fn update(contents: &str, mcro: &Macro) -> Result<String, String> {
+    let mut doc: DocumentMut = contents
+        .parse()
+        .map_err(|e| format!("Failed to parse macro file: {e}"))?;
+    let old = Format::Toml.deserialize(contents.as_bytes())?;
+    let mut fresh: DocumentMut = storage::to_toml(mcro)?
+        .parse()
+        .map_err(|e| format!("Failed to serialize macro: {e}"))?;
+
+    let old_events = doc.remove("events");
+    let fresh_events = fresh.remove("events");
+    merge(doc.as_table_mut(), fresh.as_table());
+
+    let events = match (old_events, fresh_events) {
+        (Some(Item::ArrayOfTables(old_tables)), Some(Item::ArrayOfTables(fresh_tables)))
+            if old_tables.len() == old.events.len() =>
+        {
+            Some(Item::ArrayOfTables(update_events(
+                old_tables,
+                &old.events,
+                &fresh_tables,
+                &mcro.events,
+            )))
+        }
+        // written some other way, e.g. as an inline array
+        (_, fresh_events) => fresh_events,
+    };
+    if let Some(events) = events {
+        doc.insert("events", events);
+    }
+
+    // tables are written out by position, so the ones taken from the fresh document need
+    // placing among the old ones
+    renumber(doc.as_table_mut(), &mut 0);
+    Ok(doc.to_string())
+}
+
+fn update_events(
+    old_tables: ArrayOfTables,
+    old: &[Event],
+    fresh_tables: &ArrayOfTables,
+    new: &[Event],
+) -> ArrayOfTables {
+    let mut tables = ArrayOfTables::new();
+    for (pair, fresh) in pair_up(old, new).into_iter().zip(fresh_tables.iter()) {
+        let table = match pair {
+            Pair::Same(i) => old_tables.get(i).cloned(),
+            Pair::Changed(i) => old_tables.get(i).map(|old| {
+                let mut table = old.clone();
+                merge(&mut table, fresh);
+                table
+            }),
+            Pair::Added => None,
+        };
+        tables.push(table.unwrap_or_else(|| fresh.clone()));
+    }
+    tables
+}
+
+/// Where a new event comes from
+enum Pair {
+    /// the old event at this index, unchanged
+    Same(usize),
+    /// the old event at this index, edited
+    Changed(usize),
+    /// nowhere, it is new
+    Added,
+}
+
+// for every new event, the old one it was kept as or changed from
+fn pair_up(old: &[Event], new: &[Event]) -> Vec<Pair> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let matched = if old_mid.len() * new_mid.len() <= MAX_DIFF_CELLS {
+        longest_common_subsequence(old_mid, new_mid)
+    } else {
+        vec![None; new_mid.len()]
+    };
+    // the first old event matched at or after each new one
+    let mut next_matched = vec![old_mid.len(); new_mid.len() + 1];
+    for j in (0..new_mid.len()).rev() {
+        next_matched[j] = matched[j].unwrap_or(next_matched[j + 1]);
+    }
+
+    let mut pairs: Vec<Pair> = (0..prefix).map(Pair::Same).collect();
+    let mut next_old = 0;
+    for (j, m) in matched.iter().enumerate() {
+        match m {
+            Some(i) => {
+                pairs.push(Pair::Same(prefix + i));
+                next_old = i + 1;
+            }
+            // an old event left over before the next match was changed into this one
+            None if next_old < next_matched[j] => {
+                pairs.push(Pair::Changed(prefix + next_old));
+                next_old += 1;
+            }
+            None => pairs.push(Pair::Added),
+        }
+    }
+    pairs.extend((old.len() - suffix..old.len()).map(Pair::Same));
+    pairs
+}
+
+// for every event of `new`, the index of the event of `old` it is matched with
+fn longest_common_subsequence(old: &[Event], new: &[Event]) -> Vec<Option<usize>> {
+    let width = new.len() + 1;
+    let mut lengths = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if old[i] == new[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut matched = vec![None; new.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            matched[j] = Some(i);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matched
+}
+
+// bring `old` in line with `fresh`, keeping the decor of every value which stays
+fn merge(old: &mut Table, fresh: &Table) {
+    old.retain(|key, _| fresh.contains_key(key));
+    for (key, fresh_item) in fresh.iter() {
+        match (old.get_mut(key), fresh_item) {
+            (Some(Item::Value(value)), Item::Value(fresh_value)) => {
+                if !same_value(value, fresh_value) {
+                    let decor = value.decor().clone();
+                    *value = fresh_value.clone();
+                    *value.decor_mut() = decor;
+                }
+            }
+            (Some(Item::Table(table)), Item::Table(fresh_table)) => merge(table, fresh_table),
+            (Some(Item::Value(Value::InlineTable(table))), Item::Table(fresh_table)) => {
+                merge_inline(table, fresh_table)
+            }
+            (Some(item), _) => {
+                *item = fresh_item.clone();
+                // spacing around `key = ` makes no sense in a `[table.key]` header and back
+                if let Some(mut key) = old.key_mut(key) {
+                    key.leaf_decor_mut().clear();
+                }
+            }
+            (None, _) => {
+                old.insert(key, fresh_item.clone());
+            }
+        }
+    }
+}
+
+// the same for a table written inline by hand, which stays inline
+fn merge_inline(old: &mut InlineTable, fresh: &Table) {
+    old.retain(|key, _| fresh.contains_key(key));
+    for (key, fresh_item) in fresh.iter() {
+        match (old.get_mut(key), fresh_item) {
+            (Some(Value::InlineTable(table)), Item::Table(fresh_table)) => {
+                merge_inline(table, fresh_table)
+            }
+            (Some(value), _) => {
+                if let Ok(fresh_value) = fresh_item.clone().into_value()
+                    && !same_value(value, &fresh_value)
+                {
+                    let decor = value.decor().clone();
+                    *value = fresh_value;
+                    *value.decor_mut() = decor;
+                }
+            }
+            (None, _) => {
+                if let Ok(fresh_value) = fresh_item.clone().into_value() {
+                    old.insert(key, fresh_value);
+                }
+            }
+        }
+    }
+}
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    let (mut a, mut b) = (a.clone(), b.clone());
+    a.decor_mut().clear();
+    b.decor_mut().clear();
+    a.to_string() == b.to_string()
+}
+
+fn renumber(table: &mut Table, next: &mut usize) {
+    if !table.is_dotted() {
+        table.set_position(*next);
+        *next += 1;
+    }
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(t) => renumber(t, next),
+            Item::ArrayOfTables(tables) => {
+                for t in tables.iter_mut() {
+                    renumber(t, next);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::macors::{MouseEventButton, MouseEventMove},
+        rdevin::Button,
+    };
+
+    const DOCUMENT: &str = r#"# clicks the save button
+version = 1
+description = "save" # shown by `list`
+
+# wait for the window
+[[events]]
+wait = 500
+
+[[events]]
+# the save button
+mouse_press = { x = 10.0, y = 20.0, button = "Left" }
+
+[[events]]
+[events.mouse_release]
+x = 10.0  # same spot
+y = 20.0
+button = "Left"
+
+[[events]]
+wait = 100 # settle
+
+[[events]]
+mouse_move = { x = 30.0, y = 40.0 }
+"#;
+
+    fn load() -> Macro {
+        Format::Toml.deserialize(DOCUMENT.as_bytes()).unwrap()
+    }
+
+    fn updated(mcro: &Macro) -> String {
+        let contents = update(DOCUMENT, mcro).unwrap();
+        let back = Format::Toml.deserialize(contents.as_bytes()).unwrap();
+        assert_eq!(back.events, mcro.events, "{contents}");
+        assert_eq!(back.description, mcro.description, "{contents}");
+        contents
+    }
+
+    #[test]
+    fn unchanged_document_stays_as_it_is() {
+        assert_eq!(updated(&load()), DOCUMENT);
+    }
+
+    #[test]
+    fn inserted_event_keeps_the_rest() {
+        let mut mcro = load();
+        mcro.events.insert(
+            2,
+            Event::Label {
+                name: "saving".to_string(),
+            },
+        );
+        let contents = updated(&mcro);
+        assert_eq!(
+            contents,
+            DOCUMENT.replace(
+                "\n[[events]]\n[events.mouse_release]",
+                "\n[[events]]\n[events.label]\nname = \"saving\"\n\n[[events]]\n[events.mouse_release]"
+            )
+        );
+    }
+
+    #[test]
+    fn deleted_event_takes_only_its_own_comment() {
+        let mut mcro = load();
+        mcro.events.remove(3);
+        let contents = updated(&mcro);
+        assert_eq!(
+            contents,
+            DOCUMENT.replace("[[events]]\nwait = 100 # settle\n\n", "")
+        );
+    }
+
+    #[test]
+    fn changed_event_keeps_its_comments() {
+        let mut mcro = load();
+        mcro.events[2] = Event::MouseRelease(MouseEventButton {
+            x: 12.5,
+            y: 20.0,
+            button: Button::Left,
+        });
+        mcro.events[4] = Event::MouseMove(MouseEventMove { x: 31.0, y: 40.0 });
+        mcro.description = "save it".to_string();
+        let contents = updated(&mcro);
+        assert_eq!(
+            contents,
+            DOCUMENT
+                .replace("x = 10.0  # same spot", "x = 12.5  # same spot")
+                .replace("{ x = 30.0, y = 40.0 }", "{ x = 31.0, y = 40.0 }")
+                .replace("\"save\" # shown", "\"save it\" # shown")
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use {
+    crate::{
+        config::{self, Config},
+        instance::InstanceLock,
+        listener,
+        macors::start_playback,
+        typing,
+    },
+    anyhow::{Context, Error},
+    rdevin::{EventType, Key},
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt::Write as _,
+        fs,
+        sync::mpsc::RecvTimeoutError,
+        time::{Duration, Instant, SystemTime},
+    },
+};
+
+// how often expansions.toml is checked for changes
+const RELOAD_POLL: Duration = Duration::from_secs(1);
+
+// nobody types abbreviations longer than this
+const MAX_TYPED_CHARS: usize = 64;
+
+const EXAMPLE: &str = r#"# Abbreviations for `macors expand`, e.g.
+#
+# [[expansions]]
+# trigger = ";sig"
+# text = "Best regards,\nMe ({date})"
+#
+# [[expansions]]
+# trigger = ";login"
+# macro = "login"
+#
+# Variables available in text: {date}, {time}, {datetime}, {date:<strftime format>} and
+# {clipboard}. Use {{ and }} for literal braces.
+"#;
+
+/// Abbreviations which, when typed anywhere, are replaced by text or a macro.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Expansions {
+    #[serde(default)]
+    pub expansions: Vec<Expansion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Expansion {
+    /// The typed abbreviation, e.g. ";sig"
+    pub trigger: String,
+
+    /// Text typed in place of the abbreviation, may contain variables
+    #[serde(default)]
+    pub text: Option<String>,
+
+    /// Name of a macro to play in place of the abbreviation
+    #[serde(default, rename = "macro")]
+    pub macro_name: Option<String>,
+}
+
+impl Expansions {
+    pub fn load() -> Result<Self, Error> {
+        let path = config::expansions_path();
+        if !path.exists() {
+            fs::create_dir_all(config::macors_path())?;
+            fs::write(&path, EXAMPLE)?;
+        }
+        let contents = fs::read_to_string(&path)?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn list(&self) {
+        if self.expansions.is_empty() {
+            println!(
+                "no expansions, add [[expansions]] to {}",
+                config::expansions_path().display()
+            );
+        }
+        for exp in &self.expansions {
+            match (&exp.text, &exp.macro_name) {
+                (Some(text), _) => println!("{:<27} - {text:?}", exp.trigger),
+                (None, Some(name)) => println!("{:<27} - macro {name}", exp.trigger),
+                (None, None) => println!("{:<27} - (nothing to expand to)", exp.trigger),
+            }
+        }
+    }
+}
+
+/// Watch typed keys and replace any configured abbreviation with its expansion. Runs until
+/// interrupted.
+pub fn run(cfg: &Config) -> Result<(), Error> {
+    let mut exps = Expansions::load()?;
+    let mut modified = modified_time();
+    let mut last_reload_check = Instant::now();
+    exps.list();
+
+    let rx = listener::subscribe();
+    let mut typed = String::new();
+    loop {
+        match rx.recv_timeout(RELOAD_POLL) {
+            Ok(event) => match event.event_type {
+                EventType::KeyPress(Key::Backspace) => {
+                    typed.pop();
+                }
+                EventType::KeyPress(
+                    Key::ShiftLeft | Key::ShiftRight | Key::CapsLock | Key::AltGr,
+                ) => {}
+                EventType::KeyPress(_) => {
+                    match event.name.filter(|n| !n.chars().any(char::is_control)) {
+                        Some(name) => typed.push_str(&name),
+                        // navigation, enter, shortcuts... start over
+                        None => typed.clear(),
+                    }
+                    if typed.chars().count() > MAX_TYPED_CHARS {
+                        typed.remove(0);
+                    }
+                    let matched = exps
+                        .expansions
+                        .iter()
+                        .find(|e| !e.trigger.is_empty() && typed.ends_with(&e.trigger));
+                    if let Some(exp) = matched {
+                        expand(cfg, exp);
+                        // our own typing is not input to expand
+                        listener::settle(&rx);
+                        typed.clear();
+                    }
+                }
+                EventType::ButtonPress(_) => typed.clear(),
+                _ => {}
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        if last_reload_check.elapsed() >= RELOAD_POLL {
+            last_reload_check = Instant::now();
+            let m = modified_time();
+            if m != modified {
+                modified = m;
+                match Expansions::load() {
+                    Ok(new_exps) => {
+                        println!("expansions changed, reloaded:");
+                        new_exps.list();
+                        exps = new_exps;
+                    }
+                    Err(e) => eprintln!("{e:#}, keeping old expansions"),
+                }
+            }
+        }
+    }
+}
+
+fn expand(cfg: &Config, exp: &Expansion) {
+    let _lock = match InstanceLock::acquire(&format!("expand: {}", exp.trigger), false) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("{e}");
+            return;
+        }
+    };
+    for _ in exp.trigger.chars() {
+        typing::tap(Key::Backspace);
+    }
+    if let Some(text) = &exp.text {
+        // by character, so replies like "café" or "€5" come out whole on any layout
+        match typing::TextSender::new() {
+            Ok(mut sender) => sender.send(&render(text)),
+            Err(e) => {
+                eprintln!("{e}, typing by physical key instead");
+                typing::type_text(&render(text));
+            }
+        }
+    } else if let Some(name) = &exp.macro_name
+        && let Err(abort) = start_playback(cfg, name)
+    {
+        eprintln!("playback aborted, {abort}");
+    }
+}
+
+/// Substitute the {variables} in an expansion's text
+pub fn render(text: &str) -> String {
+    let now = chrono::Local::now();
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['{', '}']) {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let Some(end) = tail.find('}').filter(|_| tail.starts_with('{')) else {
+            // unbalanced brace, keep it as is
+            out.push_str(&tail[..1]);
+            rest = &tail[1..];
+            continue;
+        };
+        let var = &tail[1..end];
+        let (name, arg) = match var.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (var, None),
+        };
+        let fmt = match (name, arg) {
+            ("date", Some(fmt)) => Some(fmt),
+            ("date", None) => Some("%Y-%m-%d"),
+            ("time", _) => Some("%H:%M"),
+            ("datetime", _) => Some("%Y-%m-%d %H:%M"),
+            _ => None,
+        };
+        if let Some(fmt) = fmt {
+            if write!(out, "{}", now.format(fmt)).is_err() {
+                eprintln!("Invalid date format {fmt:?}");
+            }
+        } else if name == "clipboard" {
+            match arboard::Clipboard::new().and_then(|mut c| c.get_text()) {
+                Ok(clip) => out.push_str(&clip),
+                Err(e) => eprintln!("Failed to read clipboard: {e}"),
+            }
+        } else {
+            eprintln!("Unknown variable {{{var}}}");
+            out.push_str(&tail[..=end]);
+        }
+        rest = &tail[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn modified_time() -> Option<SystemTime> {
+    fs::metadata(config::expansions_path())
+        .and_then(|m| m.modified())
+        .ok()
+}
@@ -0,0 +1,236 @@
+use {
+    crate::{config, macors},
+    anyhow::{Context, Error, bail},
+    serde::{Deserialize, Serialize},
+    std::{
+        fs::{self, OpenOptions},
+        io::{self, Write},
+        path::PathBuf,
+        process,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+// how often the lock holder looks for a stop request
+const STOP_POLL: Duration = Duration::from_millis(100);
+// how long an unreadable lock file is given to be written before it counts as stale
+const LOCK_WRITE_RETRIES: u32 = 5;
+const LOCK_WRITE_WAIT: Duration = Duration::from_millis(20);
+
+pub fn lock_path() -> PathBuf {
+    config::macors_path().join("macors.lock")
+}
+
+// written by `macors stop`, holds the pid which should stop
+fn stop_path() -> PathBuf {
+    config::macors_path().join("stop")
+}
+
+/// Contents of the lock file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub pid: u32,
+    /// the command line of the instance, e.g. "run mymacro -n 3"
+    pub command: String,
+    /// seconds since the unix epoch
+    pub started: u64,
+}
+
+impl LockInfo {
+    pub fn read() -> Option<Self> {
+        let contents = fs::read_to_string(lock_path()).ok()?;
+        toml::from_str(&contents).ok()
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.pid == process::id() || pid_alive(self.pid)
+    }
+
+    pub fn running_secs(&self) -> u64 {
+        now_secs().saturating_sub(self.started)
+    }
+}
+
+/// Held while this process plays or records. Removes the lock file when dropped.
+pub struct InstanceLock {
+    released: Arc<AtomicBool>,
+}
+
+impl InstanceLock {
+    /// Take the lock, failing if another live instance holds it unless `force` is set. While
+    /// held, a `macors stop` aborts the running playback or recording.
+    pub fn acquire(command: &str, force: bool) -> Result<Self, Error> {
+        let info = LockInfo {
+            pid: process::id(),
+            command: command.to_string(),
+            started: now_secs(),
+        };
+        let contents = toml::to_string(&info)?;
+        fs::create_dir_all(config::macors_path())?;
+
+        // creating the file fails if it exists, so of two instances starting together only
+        // one gets it
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(lock_path())
+        {
+            Ok(mut file) => file
+                .write_all(contents.as_bytes())
+                .context("Failed to write lock file")?,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if let Some(other) = read_settled()
+                    && other.pid != process::id()
+                    && other.is_alive()
+                    && !force
+                {
+                    bail!(
+                        "macors is already running `{}` (pid {}), use --force to run anyway",
+                        other.command,
+                        other.pid
+                    );
+                }
+                // stale or overridden, swap in ours in one go
+                let temp = config::macors_path().join(format!("macors.lock.{}", info.pid));
+                fs::write(&temp, &contents).context("Failed to write lock file")?;
+                fs::rename(&temp, lock_path()).context("Failed to replace lock file")?;
+            }
+            Err(e) => return Err(e).context("Failed to create lock file"),
+        }
+        // another instance may have replaced the same stale lock at the same moment
+        if LockInfo::read().is_none_or(|held| held.pid != info.pid) {
+            bail!("another macors took the lock at the same time");
+        }
+
+        // an old request must not stop us straight away
+        clear_stop();
+
+        let released = Arc::new(AtomicBool::new(false));
+        let released_ = released.clone();
+        thread::spawn(move || {
+            while !released_.load(Ordering::SeqCst) {
+                thread::sleep(STOP_POLL);
+                let Ok(pid) = fs::read_to_string(stop_path()) else {
+                    continue;
+                };
+                if pid.trim() == info.pid.to_string() {
+                    let _ = fs::remove_file(stop_path());
+                    println!("stop requested");
+                    macors::request_stop();
+                }
+            }
+        });
+        Ok(InstanceLock { released })
+    }
+
+    /// Forget stops asked for while nothing was running, e.g. while `run --arm` waits, so
+    /// they don't abort the run starting now
+    pub fn start_run(&self) {
+        clear_stop();
+    }
+}
+
+fn clear_stop() {
+    let _ = fs::remove_file(stop_path());
+    macors::clear_stop_request();
+}
+
+// the lock, giving an instance which just created it a moment to write it
+fn read_settled() -> Option<LockInfo> {
+    for _ in 0..LOCK_WRITE_RETRIES {
+        if let Some(info) = LockInfo::read() {
+            return Some(info);
+        }
+        thread::sleep(LOCK_WRITE_WAIT);
+    }
+    None
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        self.released.store(true, Ordering::SeqCst);
+        // don't remove a lock someone took over with --force
+        if LockInfo::read().is_some_and(|info| info.pid == process::id()) {
+            let _ = fs::remove_file(lock_path());
+        }
+    }
+}
+
+/// Ask the instance holding the lock to stop, waiting a moment for it to let go
+pub fn stop() -> Result<(), Error> {
+    let Some(info) = LockInfo::read() else {
+        println!("nothing is running");
+        return Ok(());
+    };
+    if !info.is_alive() {
+        let _ = fs::remove_file(lock_path());
+        println!(
+            "nothing is running (removed stale lock of pid {})",
+            info.pid
+        );
+        return Ok(());
+    }
+    fs::write(stop_path(), info.pid.to_string()).context("Failed to write stop request")?;
+    for _ in 0..30 {
+        thread::sleep(STOP_POLL);
+        if !fs::exists(stop_path()).unwrap_or(false) {
+            println!("stopped `{}` (pid {})", info.command, info.pid);
+            return Ok(());
+        }
+    }
+    println!(
+        "stop requested, but `{}` (pid {}) hasn't picked it up yet",
+        info.command, info.pid
+    );
+    Ok(())
+}
+
+/// Print what is currently running
+pub fn status() {
+    match LockInfo::read() {
+        None => println!("nothing is running"),
+        Some(info) if !info.is_alive() => {
+            println!("nothing is running (stale lock of pid {})", info.pid)
+        }
+        Some(info) => println!(
+            "running `{}` (pid {}) for {}s",
+            info.command,
+            info.pid,
+            info.running_secs()
+        ),
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(unix)]
+fn pid_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 only checks the process is there, it may belong to another user (EPERM)
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+#[cfg(windows)]
+fn pid_alive(pid: u32) -> bool {
+    process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {pid}"), "/NH"])
+        .output()
+        .map(|out| String::from_utf8_lossy(&out.stdout).contains(&pid.to_string()))
+        .unwrap_or(true)
+}
@@ -0,0 +1,196 @@
+use {
+    crate::{
+        config,
+        macors::{Event, Macro},
+        migrate::CURRENT_VERSION,
+        storage::{self, Format},
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        fs::{self, File},
+        io::{self, BufRead, BufReader, Write},
+        path::{Path, PathBuf},
+    },
+};
+
+const EXTENSION: &str = "journal";
+
+/// First line of a journal
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    name: String,
+    description: String,
+}
+
+/// Every further line of a journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    /// the recording dropped everything from this index on
+    Truncate {
+        truncate: usize,
+    },
+    Event(Event),
+}
+
+/// Events of a recording in progress, appended to `<name>.journal` in the macros directory as
+/// they are recorded so a crash doesn't lose them.
+pub struct Journal {
+    path: PathBuf,
+    file: Option<File>,
+    written: usize,
+}
+
+impl Journal {
+    /// Start a journal, recording goes on without one if it can't be written
+    pub fn create(name: &str, description: &str) -> Self {
+        let path = config::macros_path().join(format!("{name}.{EXTENSION}"));
+        let header = Header {
+            name: name.to_string(),
+            description: description.to_string(),
+        };
+        let file = fs::create_dir_all(config::macros_path())
+            .and_then(|()| File::create(&path))
+            .and_then(|mut file| write_line(&mut file, &header).map(|()| file));
+        let file = match file {
+            Ok(file) => Some(file),
+            Err(e) => {
+                eprintln!("Failed to create recording journal {}: {e}", path.display());
+                None
+            }
+        };
+        Journal {
+            path,
+            file,
+            written: 0,
+        }
+    }
+
+    /// A journal which writes nothing, for driving a recorder in tests
+    #[cfg(test)]
+    pub fn disabled() -> Self {
+        Journal {
+            path: PathBuf::new(),
+            file: None,
+            written: 0,
+        }
+    }
+
+    /// Append the events recorded since the last sync
+    pub fn sync(&mut self, events: &[Event]) {
+        if self.written > events.len() {
+            self.truncated(events.len());
+        }
+        for ev in &events[self.written..] {
+            self.append(&Entry::Event(ev.clone()));
+        }
+        self.written = events.len();
+    }
+
+    /// Note that the recording dropped its events from `len` on
+    pub fn truncated(&mut self, len: usize) {
+        if len < self.written {
+            self.append(&Entry::Truncate { truncate: len });
+            self.written = len;
+        }
+    }
+
+    /// The recording was saved or discarded, the journal is no longer needed
+    pub fn remove(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.path);
+    }
+
+    fn append(&mut self, entry: &Entry) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if let Err(e) = write_line(file, entry) {
+            eprintln!("Failed to write recording journal, continuing without it: {e}");
+            self.file = None;
+        }
+    }
+}
+
+fn write_line(file: &mut File, value: &impl Serialize) -> io::Result<()> {
+    let mut line = serde_json::to_string(value).map_err(io::Error::other)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+}
+
+/// Journals left behind by recordings which never finished
+pub fn interrupted() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(config::macros_path()) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == EXTENSION))
+        .collect()
+}
+
+/// Turn an interrupted journal into a macro, returns the name it was saved as. That is the
+/// name it was recorded as, unless such a macro exists by now.
+pub fn recover(path: &Path, format: Format) -> Result<String, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {e}", path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+    let header: Header = lines
+        .next()
+        .and_then(|line| line.ok())
+        .and_then(|line| serde_json::from_str(&line).ok())
+        .ok_or_else(|| format!("{} is not a recording journal", path.display()))?;
+
+    let mut events = Vec::new();
+    for line in lines {
+        // the last line may have been cut off by the crash
+        let Some(entry) = line.ok().and_then(|l| serde_json::from_str(&l).ok()) else {
+            break;
+        };
+        match entry {
+            Entry::Truncate { truncate } => events.truncate(truncate),
+            Entry::Event(ev) => events.push(ev),
+        }
+    }
+
+    let mut name = header.name;
+    if storage::exists(&name) {
+        name = format!("{name}-recovered");
+    }
+    let mcro = Macro {
+        version: CURRENT_VERSION,
+        description: header.description,
+        ended_by: None,
+        events,
+    };
+    storage::write_macro(&name, &mcro, format)?;
+    fs::remove_file(path).map_err(|e| format!("Failed to remove {}: {e}", path.display()))?;
+    Ok(name)
+}
+
+/// Offer to recover (or throw away) each interrupted journal, saving recovered ones in
+/// the given format
+pub fn prompt_recovery(format: Format) {
+    for path in interrupted() {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        print!(
+            "found an interrupted recording of {name}, [r]ecover, [d]iscard or keep it for later? "
+        );
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() {
+            return;
+        }
+        match answer.trim() {
+            "r" | "recover" => match recover(&path, format) {
+                Ok(saved) => println!("recovered as {saved}"),
+                Err(e) => eprintln!("{e}"),
+            },
+            "d" | "discard" => {
+                if let Err(e) = fs::remove_file(&path) {
+                    eprintln!("Failed to remove {}: {e}", path.display());
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use {
+    rdevin::{EventType, Key},
+    std::{
+        sync::{
+            Mutex, OnceLock,
+            mpsc::{self, Receiver, Sender},
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+// how long the hook has to stay quiet for our own synthesized events to count as through
+const SETTLE_QUIET: Duration = Duration::from_millis(100);
+// input the user keeps making (e.g. moving the mouse) mustn't hold things up for longer
+const SETTLE_MAX: Duration = Duration::from_secs(1);
+
+// rdevin can only listen once per process, so a single listener thread fans events out to
+// every subscriber
+static SUBSCRIBERS: OnceLock<Mutex<Vec<Sender<rdevin::Event>>>> = OnceLock::new();
+
+/// Subscribe to all global input events from now on. The listener thread is spawned on
+/// first use and lives until the process exits.
+pub fn subscribe() -> Receiver<rdevin::Event> {
+    let (tx, rx) = mpsc::channel();
+    let mut first = false;
+    let subscribers = SUBSCRIBERS.get_or_init(|| {
+        first = true;
+        Mutex::new(Vec::new())
+    });
+    subscribers.lock().expect("listener poisoned").push(tx);
+    if first {
+        thread::spawn(|| {
+            if let Err(e) = rdevin::listen(broadcast) {
+                eprintln!("Failed to listen for global input: {e:?}");
+            }
+        });
+    }
+    rx
+}
+
+fn broadcast(event: rdevin::Event) {
+    let Some(subscribers) = SUBSCRIBERS.get() else {
+        return;
+    };
+    // drop the subscribers whose receiver has gone away
+    subscribers
+        .lock()
+        .expect("listener poisoned")
+        .retain(|tx| tx.send(event.clone()).is_ok());
+}
+
+/// Discard events until none have come in for a moment, e.g. those caused by our own
+/// playback. They can still be on their way through the OS hook after it returns, so emptying
+/// the queue alone would miss some.
+pub fn settle(rx: &Receiver<rdevin::Event>) {
+    let deadline = Instant::now() + SETTLE_MAX;
+    while Instant::now() < deadline && rx.recv_timeout(SETTLE_QUIET).is_ok() {}
+}
+
+/// Block until `keys` has been pressed in sequence.
+pub fn wait_for_sequence(rx: &Receiver<rdevin::Event>, keys: &[Key]) {
+    let mut recent = KeySequence::default();
+    for event in rx.iter() {
+        if let EventType::KeyPress(key) = event.event_type {
+            recent.push(key);
+            if recent.ends_with(keys) {
+                return;
+            }
+        }
+    }
+}
+
+// only the tail is ever compared against, no need to keep everything
+const MAX_RECENT_KEYS: usize = 64;
+
+/// The most recently pressed keys, used to detect a key sequence such as the stop
+/// keystrokes.
+#[derive(Debug, Default, Clone)]
+pub struct KeySequence {
+    recent: Vec<Key>,
+}
+
+impl KeySequence {
+    pub fn push(&mut self, key: Key) {
+        if self.recent.len() >= MAX_RECENT_KEYS {
+            self.recent.remove(0);
+        }
+        self.recent.push(key);
+    }
+
+    /// forget the pressed keys, e.g. when a mouse event breaks up the sequence
+    pub fn clear(&mut self) {
+        self.recent.clear();
+    }
+
+    /// whether the last pressed keys are exactly `keys`
+    pub fn ends_with(&self, keys: &[Key]) -> bool {
+        !keys.is_empty() && self.recent.ends_with(keys)
+    }
+}
+
+/// Render a key sequence for display, e.g. "Escape+Escape+Escape"
+pub fn describe_keys(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|k| format!("{k:?}"))
+        .collect::<Vec<_>>()
+        .join("+")
+}
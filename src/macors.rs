@@ -1,59 +1,370 @@
 use {
-    crate::config::{self, Config, WaitStrategy},
-    rdevin::EventType,
-    std::{cell::RefCell, fs, rc::Rc, thread, time::Instant},
+    crate::{
+        binary,
+        config::{Config, Corner, Failsafe, Remap, StopAction, Terminator, WaitStrategy},
+        journal::Journal,
+        listener::{self, KeySequence},
+        migrate::CURRENT_VERSION,
+        path::PathGenerator,
+        schema::{ButtonName, KeyName},
+        secrets,
+        storage::{self, Format, load_macro, write_macro},
+        typing::{self, TextSender},
+    },
+    rdevin::{EventType, Key},
+    std::{
+        borrow::{Borrow, Cow},
+        collections::{HashMap, HashSet},
+        fmt,
+        fs::File,
+        io::BufReader,
+        ops::Range,
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc::{Receiver, RecvTimeoutError},
+        },
+        thread,
+        time::{Duration, Instant},
+    },
 };
 
+// set from another thread to stop the running playback or recording
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask the running playback to abort, or the running recording to save and finish.
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Forget a stop asked for before the playback or recording about to start
+pub fn clear_stop_request() {
+    STOP_REQUESTED.store(false, Ordering::SeqCst);
+}
+
+// how often a recording without any input checks whether it was asked to stop
+const STOP_POLL: Duration = Duration::from_millis(100);
+
 // Container for deserializing events
-#[derive(serde::Deserialize, serde::Serialize, Default, Debug, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
 pub struct Macro {
+    /// format version the macro is written in, older ones are migrated on load
+    #[serde(default)]
+    pub version: u32,
     pub description: String,
+    /// the recording limit which ended the recording, if one did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ended_by: Option<String>,
     pub events: Vec<Event>,
 }
 
-// Starts recording by using the provided event listener
-pub fn record(cfg: &Config, name: String, description: String) {
-    let mcro = Macro {
-        description,
-        events: Vec::new(),
-    };
-    let mcro = Rc::new(RefCell::new(mcro));
+impl Default for Macro {
+    fn default() -> Self {
+        Macro {
+            version: CURRENT_VERSION,
+            description: String::new(),
+            ended_by: None,
+            events: Vec::new(),
+        }
+    }
+}
 
-    // populate the starting mouse position
-    let device_state = device_query::DeviceState::new();
-    let mouse: device_query::MouseState = device_state.query_pointer();
-    let (x, y) = mouse.coords;
+/// Where a recording goes into an existing macro, instead of replacing it
+pub struct Splice {
+    pub base: Macro,
+    /// the events of `base` which the recording replaces, empty to insert
+    pub range: Range<usize>,
+}
 
-    let mouse_pos = Rc::new(RefCell::new((x as f64, y as f64)));
+impl Splice {
+    /// Put the recorded events in place of `range`, with a single wait at each seam
+    fn apply(self, recorded: Macro, seam_wait_ms: u64) -> Macro {
+        let mut events = self.base.events;
+        let tail = events.split_off(self.range.end);
+        let (held_keys, held_buttons) = held_inputs(&events);
+        events.truncate(self.range.start);
+        stitch(&mut events, recorded.events, seam_wait_ms);
+        // let go of whatever the recording left held which the rest of the macro doesn't
+        // expect to be, e.g. keys already held where the recording started
+        let (keys, buttons) = held_inputs(&events);
+        let (x, y) = last_mouse_position(&events).unwrap_or_default();
+        for key in keys.into_iter().filter(|k| !held_keys.contains(k)) {
+            events.push(Event::KeyRelease(key));
+        }
+        for button in buttons.into_iter().filter(|b| !held_buttons.contains(b)) {
+            events.push(Event::MouseRelease(MouseEventButton { x, y, button }));
+        }
+        stitch(&mut events, tail, seam_wait_ms);
+        Macro {
+            version: CURRENT_VERSION,
+            description: self.base.description,
+            ended_by: recorded.ended_by,
+            events,
+        }
+    }
+}
+
+// append `more`, merging waits meeting at the seam into the longer one, or adding one where
+// events would follow each other without any
+fn stitch(events: &mut Vec<Event>, more: Vec<Event>, seam_wait_ms: u64) {
+    let mut more = more.into_iter().peekable();
+    match (events.last_mut(), more.peek()) {
+        (Some(Event::Wait(a)), Some(Event::Wait(b))) => {
+            *a = (*a).max(*b);
+            more.next();
+        }
+        (Some(Event::Wait(_)), _) | (_, Some(Event::Wait(_))) => {}
+        (Some(_), Some(_)) => events.push(Event::Wait(seam_wait_ms)),
+        _ => {}
+    }
+    events.extend(more);
+}
 
-    let mouse_pressed = Rc::new(RefCell::new(false));
-    let recent_keys = Rc::new(RefCell::new(Vec::new()));
-    let last_event_time = Rc::new(RefCell::new(None::<Instant>));
+// Starts recording by using the provided event listener, returns once a terminator (such as
+// the stop keystrokes) is pressed or a stop is requested. Returns whether anything was saved.
+// With a splice, the (first) recording is spliced into its macro rather than saved as is.
+pub fn record(cfg: &Config, name: String, description: String, splice: Option<Splice>) -> bool {
+    let mut splice = splice;
+    let rx = listener::subscribe();
+    let mut segment = 1;
+    let mut swallow_releases = Vec::new();
+    loop {
+        let segment_name = if segment == 1 {
+            name.clone()
+        } else {
+            format!("{name}-{segment}")
+        };
+        let mut recorder = Recorder::new(cfg, &segment_name, description.clone());
+        recorder.swallow_releases = swallow_releases;
+        if let Some(splice) = &splice {
+            let (keys, buttons) = held_inputs(&splice.base.events[..splice.range.start]);
+            recorder.seed_held(keys, buttons);
+            recorder.spliced_names = placeholder_names(&splice.base.events)
+                .map(str::to_string)
+                .collect();
+        }
+        let action = loop {
+            match rx.recv_timeout(STOP_POLL) {
+                Ok(event) => {
+                    if let Some(action) = recorder.handle(event) {
+                        break action;
+                    }
+                    recorder.journal.sync(&recorder.mcro.events);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break StopAction::Save,
+            }
+            if STOP_REQUESTED.swap(false, Ordering::SeqCst) {
+                break StopAction::Save;
+            }
+            if let Some(limit) = recorder.limit_reached() {
+                println!("recording stopped, reached the {limit}");
+                recorder.mcro.ended_by = Some(limit);
+                break StopAction::Save;
+            }
+        };
 
-    // set the recording_initial_wait_ms
-    mcro.borrow_mut()
-        .events
-        .push(Event::Wait(cfg.recording_initial_wait_ms));
+        // only what's spliced into keeps the file as it was, a new recording replaces it
+        let spliced = splice.is_some();
+        if action != StopAction::Discard
+            && let Some(splice) = splice.take()
+        {
+            let recorded = std::mem::take(&mut recorder.mcro);
+            recorder.mcro = splice.apply(recorded, cfg.recording_initial_wait_ms);
+        }
+        match action {
+            StopAction::Discard => {
+                recorder.journal.remove();
+                println!("recording of {segment_name} discarded");
+                return segment > 1;
+            }
+            StopAction::Save => {
+                save_recording(cfg, &segment_name, &recorder.mcro, spliced);
+                recorder.journal.remove();
+                return true;
+            }
+            StopAction::SaveAndReplay => {
+                save_recording(cfg, &segment_name, &recorder.mcro, spliced);
+                recorder.journal.remove();
+                println!("replaying {segment_name}");
+                if let Err(abort) = play_events(cfg, &recorder.mcro.events, 0.0, 0.0) {
+                    eprintln!("playback aborted, {abort}");
+                }
+                return true;
+            }
+            StopAction::SaveAndContinue => {
+                save_recording(cfg, &segment_name, &recorder.mcro, spliced);
+                recorder.journal.remove();
+                segment += 1;
+                println!("saved {segment_name}, continuing as {name}-{segment}");
+                // the terminator's keys are still being let go of
+                swallow_releases = recorder.pressed_keys;
+            }
+        }
+    }
+}
+
+/// A recording in progress
+struct Recorder<'a> {
+    cfg: &'a Config,
+    mcro: Macro,
+    mouse_pos: (f64, f64),
+    mouse_pressed: bool,
+    recent_keys: KeySequence,
+    /// keys currently held down, for matching chords
+    pressed_keys: Vec<Key>,
+    terminators: Vec<Terminator>,
+    last_event_time: Option<Instant>,
+    /// when the recording was paused, if it is
+    paused_at: Option<Instant>,
+    /// how long the recording spent paused before `paused_at`
+    paused_for: Duration,
+    /// keys whose press wasn't recorded, so their release shouldn't be either
+    swallow_releases: Vec<Key>,
+    /// buttons whose press was filtered out, so their release is too
+    filtered_buttons: Vec<rdevin::Button>,
+    /// keys and buttons already held by the macro spliced into when recording started
+    held_at_start: (Vec<Key>, Vec<rdevin::Button>),
+    /// names of the secrets and labels in the macro spliced into, so new ones don't clash
+    spliced_names: Vec<String>,
+    /// when the last mouse move was recorded, for sampling
+    last_move_time: Option<Instant>,
+    journal: Journal,
+    started: Instant,
+    /// events recorded other than waits, for the event count limit
+    input_events: usize,
+    /// when the last input arrived, for the idle timeout
+    last_input: Instant,
+    /// typing is replaced by a secret while private
+    private: bool,
+    /// whether this stretch of private mode has its secret yet
+    secret_recorded: bool,
+}
+
+impl<'a> Recorder<'a> {
+    fn new(cfg: &'a Config, name: &str, description: String) -> Self {
+        let journal = Journal::create(name, &description);
+
+        // populate the starting mouse position
+        let device_state = device_query::DeviceState::new();
+        let mouse: device_query::MouseState = device_state.query_pointer();
+        let (x, y) = mouse.coords;
+
+        Self::starting_at(cfg, journal, description, (x as f64, y as f64))
+    }
+
+    fn starting_at(
+        cfg: &'a Config,
+        journal: Journal,
+        description: String,
+        mouse_pos: (f64, f64),
+    ) -> Self {
+        let mut mcro = Macro {
+            version: CURRENT_VERSION,
+            description,
+            ended_by: None,
+            events: Vec::new(),
+        };
+
+        // set the recording_initial_wait_ms
+        mcro.events.push(Event::Wait(cfg.recording_initial_wait_ms));
+
+        Recorder {
+            cfg,
+            mcro,
+            mouse_pos,
+            mouse_pressed: false,
+            recent_keys: KeySequence::default(),
+            pressed_keys: Vec::new(),
+            terminators: cfg.terminators(),
+            last_event_time: None,
+            paused_at: None,
+            paused_for: Duration::ZERO,
+            swallow_releases: Vec::new(),
+            filtered_buttons: Vec::new(),
+            held_at_start: (Vec::new(), Vec::new()),
+            spliced_names: Vec::new(),
+            last_move_time: None,
+            journal,
+            started: Instant::now(),
+            input_events: 0,
+            last_input: Instant::now(),
+            private: false,
+            secret_recorded: false,
+        }
+    }
+
+    /// Record the event, returns what to do once a terminator has been pressed
+    fn handle(&mut self, event: rdevin::Event) -> Option<StopAction> {
+        self.last_input = Instant::now();
+        match event.event_type {
+            EventType::KeyPress(key) if !self.pressed_keys.contains(&key) => {
+                self.pressed_keys.push(key)
+            }
+            EventType::KeyRelease(key) => self.pressed_keys.retain(|k| *k != key),
+            _ => {}
+        }
+        if let Some(paused_at) = self.paused_at {
+            return self.handle_paused(event, paused_at);
+        }
+
+        if let EventType::KeyPress(key) = event.event_type
+            && self.private
+            && typing::is_typing_key(key)
+            && self.cfg.record_filter.allows_key(&key)
+        {
+            // typed into the secret, which is filled in at playback
+            self.recent_keys.push(key);
+            if !self.swallow_releases.contains(&key) {
+                self.swallow_releases.push(key);
+            }
+            if !self.secret_recorded {
+                self.secret_recorded = true;
+                let name = self.free_name("secret");
+                println!("adding event: secret {name}");
+                self.push(Event::Secret { name });
+            }
+            return self.check_keystrokes(&event.event_type);
+        }
+
+        if !self.allowed(&event.event_type) {
+            // follow the pointer and key sequences, but leave no trace (nor wait) in the macro
+            match event.event_type {
+                EventType::KeyPress(key) => self.recent_keys.push(key),
+                EventType::MouseMove { x, y } => {
+                    self.mouse_pos = (x, y);
+                    self.recent_keys.clear();
+                }
+                EventType::ButtonPress(_) | EventType::ButtonRelease(_) => self.recent_keys.clear(),
+                _ => {}
+            }
+            return self.check_keystrokes(&event.event_type);
+        }
 
-    let cfg_ = cfg.clone();
-    let mcro_ = mcro.clone();
-    let recent_keys_ = recent_keys.clone();
-    let callback = move |event: rdevin::Event| {
         let op_ev = match event.event_type {
             EventType::KeyPress(key) => {
-                recent_keys_.borrow_mut().push(key);
-                println!("adding event: keypress {:?}", key);
-                Some(Event::KeyPress(key))
+                self.recent_keys.push(key);
+                // the character typed, which depends on the keyboard layout
+                let text = event
+                    .name
+                    .filter(|t| !t.is_empty() && !t.chars().any(char::is_control));
+                println!("adding event: keypress {:?} {:?}", key, text);
+                Some(Event::KeyPress(KeyStroke { key, text }))
             }
             EventType::KeyRelease(key) => {
-                println!("adding event: keyrelease {:?}", key);
-                Some(Event::KeyRelease(key))
+                if let Some(i) = self.swallow_releases.iter().position(|k| *k == key) {
+                    self.swallow_releases.remove(i);
+                    None
+                } else {
+                    println!("adding event: keyrelease {:?}", key);
+                    Some(Event::KeyRelease(key))
+                }
             }
             EventType::ButtonPress(button) => {
-                let m = *mouse_pos.borrow();
+                let m = self.mouse_pos;
                 println!("adding event: mouse press {button:?} at {m:?}");
-                recent_keys_.replace(Vec::new());
-                mouse_pressed.replace(true);
+                self.recent_keys.clear();
+                self.mouse_pressed = true;
                 Some(Event::MousePress(MouseEventButton {
                     x: m.0,
                     y: m.1,
@@ -61,10 +372,10 @@ pub fn record(cfg: &Config, name: String, description: String) {
                 }))
             }
             EventType::ButtonRelease(button) => {
-                let m = *mouse_pos.borrow();
+                let m = self.mouse_pos;
                 println!("adding event: mouse release {button:?} at {m:?}");
-                recent_keys_.replace(Vec::new());
-                mouse_pressed.replace(false);
+                self.recent_keys.clear();
+                self.mouse_pressed = false;
                 Some(Event::MouseRelease(MouseEventButton {
                     x: m.0,
                     y: m.1,
@@ -73,9 +384,11 @@ pub fn record(cfg: &Config, name: String, description: String) {
             }
             EventType::MouseMove { x, y } => {
                 println!("adding event mousemove (x, y):  {:?}", (x, y));
-                mouse_pos.replace((x, y));
-                recent_keys_.replace(Vec::new());
-                if cfg_.record_non_drag_mouse_moves || *mouse_pressed.borrow() {
+                self.mouse_pos = (x, y);
+                self.recent_keys.clear();
+                if (self.cfg.record_non_drag_mouse_moves || self.mouse_pressed)
+                    && self.sample_move()
+                {
                     Some(Event::MouseMove(MouseEventMove { x, y }))
                 } else {
                     None
@@ -87,181 +400,910 @@ pub fn record(cfg: &Config, name: String, description: String) {
             } => None,
         };
         if let Some(ev) = op_ev {
-            match cfg_.wait_strategy {
-                WaitStrategy::Actual => {
-                    let now = Instant::now();
-                    if let Some(last_event_time) = last_event_time.borrow_mut().take() {
-                        let ms = now.duration_since(last_event_time).as_millis() as u64;
-                        mcro_.borrow_mut().events.push(Event::Wait(ms));
-                        println!("adding event: wait {}", ms);
-                    }
-                    last_event_time.replace(Some(now));
+            // typing after e.g. Tab or a click goes somewhere else, so into a secret of its own
+            if matches!(ev, Event::KeyPress(_) | Event::MousePress(_)) {
+                self.secret_recorded = false;
+            }
+            self.push(ev);
+        }
+        self.check_keystrokes(&event.event_type)
+    }
+
+    // add the event along with the wait leading up to it
+    fn push(&mut self, ev: Event) {
+        match self.cfg.wait_strategy {
+            WaitStrategy::Actual => {
+                let now = Instant::now();
+                if let Some(last_event_time) = self.last_event_time.take() {
+                    let ms = now.duration_since(last_event_time).as_millis() as u64;
+                    self.mcro.events.push(Event::Wait(ms));
+                    println!("adding event: wait {}", ms);
                 }
-                WaitStrategy::ConstantMS(ms) => {
-                    // TODO more complex constant wait strategy
-                    mcro_.borrow_mut().events.push(Event::Wait(ms));
+                self.last_event_time = Some(now);
+            }
+            WaitStrategy::ConstantMS(ms) => {
+                // TODO more complex constant wait strategy
+                self.mcro.events.push(Event::Wait(ms));
+            }
+        };
+        self.append(ev);
+    }
+
+    // add the event as it is, counting it towards the event count limit
+    fn append(&mut self, ev: Event) {
+        if !matches!(ev, Event::Wait(_)) {
+            self.input_events += 1;
+        }
+        self.mcro.events.push(ev);
+    }
+
+    // after events were taken back out of the macro
+    fn truncated(&mut self) {
+        let events = &self.mcro.events;
+        self.input_events = events
+            .iter()
+            .filter(|e| !matches!(e, Event::Wait(_)))
+            .count();
+        self.journal.truncated(events.len());
+    }
+
+    // the recording limit which has been reached, if any
+    fn limit_reached(&self) -> Option<String> {
+        let limits = &self.cfg.record_limits;
+        let paused = self.paused_for + self.paused_at.map_or(Duration::ZERO, |p| p.elapsed());
+        if let Some(secs) = limits.max_duration_secs
+            && self.started.elapsed().saturating_sub(paused) >= Duration::from_secs(secs)
+        {
+            return Some(format!("max duration of {secs}s"));
+        }
+        if let Some(max) = limits.max_events
+            && self.input_events >= max
+        {
+            return Some(format!("max event count of {max}"));
+        }
+        if let Some(secs) = limits.idle_timeout_secs
+            && self.last_input.elapsed() >= Duration::from_secs(secs)
+        {
+            return Some(format!("idle timeout of {secs}s"));
+        }
+        None
+    }
+
+    // whether enough time has passed since the last recorded mouse move to record another
+    fn sample_move(&mut self) -> bool {
+        let now = Instant::now();
+        let interval = Duration::from_millis(self.cfg.mouse_move_sample_ms);
+        if self
+            .last_move_time
+            .is_some_and(|last| now.duration_since(last) < interval)
+        {
+            return false;
+        }
+        self.last_move_time = Some(now);
+        true
+    }
+
+    // whether the record filter lets the event into the macro
+    fn allowed(&mut self, event_type: &EventType) -> bool {
+        let filter = &self.cfg.record_filter;
+        match event_type {
+            EventType::KeyPress(key) | EventType::KeyRelease(key) => filter.allows_key(key),
+            EventType::ButtonPress(button) => {
+                let (x, y) = self.mouse_pos;
+                let allowed = !filter.keyboard_only && filter.allows_point(x, y);
+                if !allowed {
+                    self.filtered_buttons.push(*button);
                 }
-            };
-            mcro_.borrow_mut().events.push(ev);
-        }
-        // break if the end keys recent_keys match the stop keys
-        if recent_keys_.borrow().ends_with(&cfg_.stop_keystrokes) {
-            let mut events = mcro_.borrow().clone();
-            let mut to_pop = cfg_.stop_keystrokes.clone();
-            // move through the events in reverse popping everything that matches the stop keys
-            for i in (0..events.events.len()).rev() {
-                if let Event::KeyPress(key) = &events.events[i] {
-                    if to_pop.last() == Some(key) {
-                        to_pop.pop();
-                        if to_pop.is_empty() {
-                            events.events.pop();
-                            break;
-                        }
+                allowed
+            }
+            // a release goes wherever its press went
+            EventType::ButtonRelease(button) => {
+                match self.filtered_buttons.iter().position(|b| b == button) {
+                    Some(i) => {
+                        self.filtered_buttons.remove(i);
+                        false
                     }
+                    None => true,
                 }
-                events.events.pop();
             }
+            EventType::MouseMove { x, y } => !filter.keyboard_only && filter.allows_point(*x, *y),
+            EventType::Wheel { .. } => true,
+        }
+    }
 
-            let mut toml_string =
-                toml::to_string(&events).expect("Failed to serialize recorded events");
+    // act on terminators and the pause keystrokes, stripping their keys from the macro
+    fn check_keystrokes(&mut self, event_type: &EventType) -> Option<StopAction> {
+        // break if the end keys recent_keys match a terminator
+        if let Some(terminator) = self.terminator(event_type) {
+            let recorded = self.recorded(&terminator.keys);
+            pop_key_sequence(&mut self.mcro.events, &recorded);
+            strip_chord(&mut self.mcro.events, &terminator.chord);
+            return Some(terminator.action);
+        }
+        if self.recent_keys.ends_with(&self.cfg.pause_keystrokes) {
+            self.recent_keys.clear();
+            let recorded = self.recorded(&self.cfg.pause_keystrokes);
+            pop_key_sequence(&mut self.mcro.events, &recorded);
+            self.truncated();
+            self.release_held();
+            self.paused_at = Some(Instant::now());
+            println!(
+                "recording paused, press {} to resume",
+                listener::describe_keys(&self.cfg.resume_keystrokes)
+            );
+        }
+        if self.recent_keys.ends_with(&self.cfg.label_keystrokes) {
+            self.strip_hotkey(&self.cfg.label_keystrokes);
+            let name = self.free_name("label");
+            println!("adding event: label {name}");
+            self.push(Event::Label { name });
+        }
+        if self.recent_keys.ends_with(&self.cfg.private_keystrokes) {
+            self.strip_hotkey(&self.cfg.private_keystrokes);
+            self.private = !self.private;
+            self.secret_recorded = false;
+            if self.private {
+                println!("private mode, typing is recorded as a secret");
+            } else {
+                println!("private mode ended");
+            }
+        }
+        None
+    }
 
-            // manually make the toml string nicer
-            toml_string = toml_string.replace("[[events]]\n\n", "[[events]]\n");
+    // take a hotkey's keys back out of the recording, along with their coming releases
+    fn strip_hotkey(&mut self, keys: &[Key]) {
+        self.recent_keys.clear();
+        let recorded = self.recorded(keys);
+        pop_key_sequence(&mut self.mcro.events, &recorded);
+        self.truncated();
+        for key in keys {
+            if self.pressed_keys.contains(key) && !self.swallow_releases.contains(key) {
+                self.swallow_releases.push(*key);
+            }
+        }
+    }
 
-            let macros_dir = config::macros_path();
-            fs::create_dir_all(&macros_dir).expect("Failed to create macros directory");
-            let file_path = macros_dir.join(format!("{name}.toml"));
-            fs::write(file_path, toml_string).expect("Failed to save macro file");
+    // while paused only the resume keystrokes and terminators are looked at
+    fn handle_paused(&mut self, event: rdevin::Event, paused_at: Instant) -> Option<StopAction> {
+        let EventType::KeyPress(key) = event.event_type else {
+            if let EventType::MouseMove { x, y } = event.event_type {
+                self.mouse_pos = (x, y);
+            }
+            return None;
+        };
+        self.recent_keys.push(key);
+        if let Some(terminator) = self.terminator(&event.event_type) {
+            // nothing of it was recorded
+            return Some(terminator.action);
+        }
+        if self.recent_keys.ends_with(&self.cfg.resume_keystrokes) {
+            self.recent_keys.clear();
+            // the paused time doesn't count towards the next wait
+            if let Some(last_event_time) = &mut self.last_event_time {
+                *last_event_time += paused_at.elapsed();
+            }
+            // nor towards the max duration
+            self.paused_for += paused_at.elapsed();
+            self.swallow_releases
+                .extend_from_slice(&self.cfg.resume_keystrokes);
+            self.paused_at = None;
+            println!("recording resumed");
+        }
+        None
+    }
 
-            // beep to let the user know we're done
-            let middle_e_hz = 329;
-            let a_bit_more_than_a_second_and_a_half_ms = 100;
-            actually_beep::beep_with_hz_and_millis(
-                middle_e_hz,
-                a_bit_more_than_a_second_and_a_half_ms,
-            )
-            .unwrap();
+    // the keys of a sequence which made it past the record filter
+    fn recorded(&self, keys: &[Key]) -> Vec<Key> {
+        keys.iter()
+            .copied()
+            .filter(|k| self.cfg.record_filter.allows_key(k))
+            .filter(|k| !self.private || !typing::is_typing_key(*k))
+            .collect()
+    }
 
-            // exit with a silent panic (as you can't exit the listen loop within rdevin currently)
-            let _ = std::fs::File::create("/dev/null").map(|_f| -> Result<(), std::io::Error> {
-                std::panic::set_hook(Box::new(|_| {})); // Silence panic
-                panic!("silent panic");
-            });
+    // the terminator completed by this event, if any
+    fn terminator(&self, event_type: &EventType) -> Option<Terminator> {
+        let EventType::KeyPress(key) = event_type else {
+            return None;
+        };
+        self.terminators
+            .iter()
+            .find(|t| {
+                self.recent_keys.ends_with(&t.keys)
+                    || (t.chord.contains(key)
+                        && t.chord.iter().all(|k| self.pressed_keys.contains(k)))
+            })
+            .cloned()
+    }
+
+    // the first `<prefix>-<n>` name not yet taken in the macro being recorded or spliced into
+    fn free_name(&self, prefix: &str) -> String {
+        let taken: HashSet<&str> = placeholder_names(&self.mcro.events)
+            .chain(self.spliced_names.iter().map(String::as_str))
+            .collect();
+        (1..)
+            .map(|n| format!("{prefix}-{n}"))
+            .find(|name| !taken.contains(name.as_str()))
+            .expect("names run out")
+    }
+
+    // start out with keys and buttons the macro spliced into holds at this point
+    fn seed_held(&mut self, keys: Vec<Key>, buttons: Vec<rdevin::Button>) {
+        for key in &keys {
+            if !self.pressed_keys.contains(key) {
+                self.pressed_keys.push(*key);
+            }
         }
-    };
+        self.mouse_pressed = !buttons.is_empty();
+        self.held_at_start = (keys, buttons);
+    }
 
-    if let Err(_e) = rdevin::listen(callback.clone()) {
-        // ignore the error which will occur at the end of the recording
+    // record releases for everything still held down, so nothing stays pressed while paused
+    fn release_held(&mut self) {
+        let (held_keys, held_buttons) = &self.held_at_start;
+        let (x, y) = self.mouse_pos;
+        let seeded: Vec<Event> = held_keys
+            .iter()
+            .map(|key| {
+                Event::KeyPress(KeyStroke {
+                    key: *key,
+                    text: None,
+                })
+            })
+            .chain(held_buttons.iter().map(|button| {
+                Event::MousePress(MouseEventButton {
+                    x,
+                    y,
+                    button: *button,
+                })
+            }))
+            .collect();
+        let (keys, buttons) = held_inputs(seeded.iter().chain(&self.mcro.events));
+        self.held_at_start = (Vec::new(), Vec::new());
+        for key in keys {
+            println!("adding event: keyrelease {:?}", key);
+            self.append(Event::KeyRelease(key));
+            // the real release will come later
+            self.swallow_releases.push(key);
+        }
+        for button in buttons {
+            let m = self.mouse_pos;
+            println!("adding event: mouse release {button:?} at {m:?}");
+            self.append(Event::MouseRelease(MouseEventButton {
+                x: m.0,
+                y: m.1,
+                button,
+            }));
+        }
+        self.mouse_pressed = false;
     }
 }
 
-// Starts playback by deserializing events and passing them to the provided event listener
-pub fn start_playback(_cfg: &Config, name: &str) {
-    let macros_dir = config::macros_path();
-    let file_path = macros_dir.join(format!("{}.toml", name));
-
-    // get the macro for the name and deserialize it
+/// Move through the events in reverse popping everything up to and including the presses of
+/// the key sequence, so that it doesn't end up in the recording.
+fn pop_key_sequence(events: &mut Vec<Event>, keys: &[Key]) {
+    let mut to_pop = keys.to_vec();
+    for i in (0..events.len()).rev() {
+        if let Event::KeyPress(stroke) = &events[i]
+            && to_pop.last() == Some(&stroke.key)
+        {
+            to_pop.pop();
+            if to_pop.is_empty() {
+                events.truncate(i);
+                return;
+            }
+        }
+    }
+    // the sequence was never recorded, leave everything be
+}
 
-    let Ok(contents) = fs::read_to_string(file_path) else {
-        println!("Macro not found");
-        return;
-    };
+/// Remove the presses of the chord's keys which are still held by the end of the events,
+/// together with the wait before each of them.
+fn strip_chord(events: &mut Vec<Event>, chord: &[Key]) {
+    let mut released = Vec::new();
+    let mut i = events.len();
+    while i > 0 && released.len() < chord.len() {
+        i -= 1;
+        match &events[i] {
+            Event::KeyRelease(key) if chord.contains(key) && !released.contains(key) => {
+                released.push(*key)
+            }
+            // a held key may repeat its press, strip all of them
+            Event::KeyPress(KeyStroke { key, .. })
+                if chord.contains(key) && !released.contains(key) =>
+            {
+                events.remove(i);
+                if i > 0 && matches!(events[i - 1], Event::Wait(_)) {
+                    events.remove(i - 1);
+                    i -= 1;
+                }
+            }
+            _ => {}
+        }
+    }
+}
 
-    let evs: Macro = match toml::from_str(&contents) {
-        Ok(evs) => evs,
-        Err(e) => {
-            println!("Failed to deserialize macro file: {:?}", e);
-            return;
+/// The keys and mouse buttons which are pressed but not yet released by the end of the events
+fn held_inputs<'e>(events: impl IntoIterator<Item = &'e Event>) -> (Vec<Key>, Vec<rdevin::Button>) {
+    let mut keys: Vec<Key> = Vec::new();
+    let mut buttons: Vec<rdevin::Button> = Vec::new();
+    for ev in events {
+        match ev {
+            Event::KeyPress(stroke) if !keys.contains(&stroke.key) => keys.push(stroke.key),
+            Event::KeyRelease(key) => keys.retain(|k| k != key),
+            Event::MousePress(m) if !buttons.contains(&m.button) => buttons.push(m.button),
+            Event::MouseRelease(m) => buttons.retain(|b| *b != m.button),
+            _ => {}
         }
+    }
+    (keys, buttons)
+}
+
+fn save_recording(cfg: &Config, name: &str, events: &Macro, spliced: bool) {
+    let saved = if spliced {
+        write_macro(name, events, cfg.default_format)
+    } else {
+        storage::replace_macro(name, events, cfg.default_format)
     };
+    saved.expect("Failed to save macro file");
 
-    for ev in evs.events {
-        // TODO check for stop
-        ev.simulate();
-    }
+    // beep to let the user know we're done
+    let middle_e_hz = 329;
+    let a_bit_more_than_a_second_and_a_half_ms = 100;
+    actually_beep::beep_with_hz_and_millis(middle_e_hz, a_bit_more_than_a_second_and_a_half_ms)
+        .unwrap();
 }
 
-pub fn start_playback_with_offset(_cfg: &Config, name: &str) {
-    let macros_dir = config::macros_path();
-    let file_path = macros_dir.join(format!("{}.toml", name));
+// Starts playback by deserializing events and passing them to the provided event listener
+pub fn start_playback(cfg: &Config, name: &str) -> Result<(), Abort> {
+    if let Some((path, Format::Binary)) = storage::find(name) {
+        return stream_playback(cfg, name, &path, false);
+    }
+    play_loaded(cfg, name, false)
+}
 
-    let Ok(contents) = fs::read_to_string(file_path) else {
-        println!("Macro not found");
-        return;
-    };
+pub fn start_playback_with_offset(cfg: &Config, name: &str) -> Result<(), Abort> {
+    if let Some((path, Format::Binary)) = storage::find(name) {
+        return stream_playback(cfg, name, &path, true);
+    }
+    play_loaded(cfg, name, true)
+}
 
-    let evs: Macro = match toml::from_str(&contents) {
+fn play_loaded(cfg: &Config, name: &str, relative: bool) -> Result<(), Abort> {
+    // get the macro for the name and deserialize it
+    let evs = match load_macro(name) {
         Ok(evs) => evs,
         Err(e) => {
-            println!("Failed to deserialize macro file: {:?}", e);
-            return;
+            println!("{e}");
+            return Ok(());
         }
     };
 
-    // Find the first mouse event to determine the offset
-    let mut first_mouse_event = None;
-    for ev in &evs.events {
-        match ev {
-            Event::MousePress(m) | Event::MouseRelease(m) => {
-                first_mouse_event = Some((m.x, m.y));
-                break;
-            }
-            Event::MouseMove(m) => {
-                first_mouse_event = Some((m.x, m.y));
-                break;
-            }
-            _ => {}
+    let (dx, dy) = if relative {
+        mouse_offset(&evs.events)
+    } else {
+        (0.0, 0.0)
+    };
+    play_events(cfg, &evs.events, dx, dy)
+}
+
+// binary macros are played while they are read, everything needed up front is in the header
+fn stream_playback(cfg: &Config, name: &str, path: &Path, relative: bool) -> Result<(), Abort> {
+    let reader = match File::open(path) {
+        Ok(file) => binary::Reader::new(BufReader::new(file)),
+        Err(e) => Err(format!("Failed to read macro file: {e}")),
+    };
+    let reader = match reader {
+        Ok(reader) => reader,
+        Err(e) => {
+            println!("{e}");
+            return Ok(());
         }
+    };
+    // migrating an older macro takes all of it
+    if reader.header().version != CURRENT_VERSION {
+        return play_loaded(cfg, name, relative);
     }
+    let header = reader.header();
+    let (dx, dy) = if relative {
+        offset_from(header.origin)
+    } else {
+        (0.0, 0.0)
+    };
+    let secrets =
+        secrets::resolve_names(header.secrets.iter().map(String::as_str)).map_err(Abort::Secret)?;
+    let total = header.len;
+    let events = reader.map(|ev| ev.map_err(Abort::Read));
+    play_stream(cfg, secrets, events, total, dx, dy, &mut |_, _| {})
+}
 
-    let (dx, dy) = if let Some((fx, fy)) = first_mouse_event {
+/// Where the first mouse event of the macro happens
+pub fn first_mouse_position(events: &[Event]) -> Option<(f64, f64)> {
+    first_mouse_position_in(events)
+}
+
+fn first_mouse_position_in<'e>(events: impl IntoIterator<Item = &'e Event>) -> Option<(f64, f64)> {
+    events.into_iter().find_map(|ev| match ev {
+        Event::MousePress(m) | Event::MouseRelease(m) => Some((m.x, m.y)),
+        Event::MouseMove(m) => Some((m.x, m.y)),
+        _ => None,
+    })
+}
+
+// the names of the secrets and labels of the macro
+fn placeholder_names(events: &[Event]) -> impl Iterator<Item = &str> {
+    events.iter().filter_map(|ev| match ev {
+        Event::Secret { name } | Event::Label { name } => Some(name.as_str()),
+        _ => None,
+    })
+}
+
+// where the last mouse event of the macro happens
+fn last_mouse_position(events: &[Event]) -> Option<(f64, f64)> {
+    first_mouse_position_in(events.iter().rev())
+}
+
+/// The offset from the first mouse event of the macro to the current mouse position
+pub fn mouse_offset(events: &[Event]) -> (f64, f64) {
+    offset_from(first_mouse_position(events))
+}
+
+fn offset_from(first_mouse_event: Option<(f64, f64)>) -> (f64, f64) {
+    if let Some((fx, fy)) = first_mouse_event {
         let device_state = device_query::DeviceState::new();
         let mouse: device_query::MouseState = device_state.query_pointer();
         let (cur_x, cur_y) = mouse.coords;
         (cur_x as f64 - fx, cur_y as f64 - fy)
     } else {
         (0.0, 0.0)
+    }
+}
+
+/// Why a playback was cut short.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Abort {
+    /// the pointer was thrown into a failsafe corner
+    Corner(Corner),
+    /// the pointer is no longer where playback last put it
+    MouseMoved { x: f64, y: f64 },
+    /// stop was requested from elsewhere
+    Stopped,
+    /// a secret placeholder has no value to type
+    Secret(String),
+    /// the remap table doesn't exist
+    Remap(String),
+    /// the rest of a streamed macro couldn't be read
+    Read(String),
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Abort::Corner(c) => write!(f, "failsafe: pointer entered the {c:?} corner"),
+            Abort::MouseMoved { x, y } => {
+                write!(f, "failsafe: pointer was moved by hand to ({x}, {y})")
+            }
+            Abort::Stopped => write!(f, "stop requested"),
+            Abort::Secret(e) | Abort::Remap(e) => write!(f, "{e}"),
+            Abort::Read(e) => write!(f, "failed to read the macro, {e}"),
+        }
+    }
+}
+
+/// Plays the events, offsetting all mouse positions by (dx, dy). If the failsafe trips
+/// everything still held down is released before returning.
+pub fn play_events(cfg: &Config, events: &[Event], dx: f64, dy: f64) -> Result<(), Abort> {
+    play_events_with_progress(cfg, events, dx, dy, &mut |_, _| {})
+}
+
+/// Like `play_events`, calling `progress(index, total)` before each event is played.
+pub fn play_events_with_progress(
+    cfg: &Config,
+    events: &[Event],
+    dx: f64,
+    dy: f64,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), Abort> {
+    let secrets = secrets::resolve(events).map_err(Abort::Secret)?;
+    let stream = events.iter().map(Ok);
+    play_stream(cfg, secrets, stream, events.len(), dx, dy, progress)
+}
+
+// plays events as the iterator yields them, `total` is only used for the progress
+fn play_stream<E: Borrow<Event>>(
+    cfg: &Config,
+    secrets: HashMap<String, String>,
+    events: impl Iterator<Item = Result<E, Abort>>,
+    total: usize,
+    dx: f64,
+    dy: f64,
+    progress: &mut dyn FnMut(usize, usize),
+) -> Result<(), Abort> {
+    let remap = match &cfg.remap {
+        Some(name) => Some(cfg.remap_profile(name).map_err(Abort::Remap)?),
+        None => None,
     };
+    let mut player = Player::new(cfg, secrets, remap);
+    let mut events = events.enumerate();
+    let res = events.try_for_each(|(i, ev)| {
+        progress(i, total);
+        player.play(ev?.borrow(), dx, dy)
+    });
+    if res.is_err() {
+        player.release_held();
+    }
+    // a stop arriving after the last event was played has nothing left to stop
+    STOP_REQUESTED.store(false, Ordering::SeqCst);
+    res
+}
 
-    for ev in evs.events {
+// granularity at which waits are split up to poll the failsafe and stop requests
+const FAILSAFE_POLL_MS: u64 = 10;
+
+struct Player<'a> {
+    cfg: &'a Config,
+    failsafe: &'a Failsafe,
+    device_state: device_query::DeviceState,
+    /// watched for the pause keystrokes
+    input: Receiver<rdevin::Event>,
+    recent_keys: KeySequence,
+    screen: Option<(f64, f64)>,
+    /// where playback last put the pointer (or where it was when playback began)
+    last_pos: (f64, f64),
+    held_keys: Vec<rdevin::Key>,
+    held_buttons: Vec<rdevin::Button>,
+    path: PathGenerator,
+    secrets: HashMap<String, String>,
+    remap: Option<&'a Remap>,
+    /// set when typing by character instead of by physical key
+    text_sender: Option<TextSender>,
+    /// keys sent as text, whose release isn't played
+    typed_keys: Vec<rdevin::Key>,
+    /// shift keys not pressed (yet) while typing by character
+    deferred_keys: Vec<rdevin::Key>,
+}
+
+impl<'a> Player<'a> {
+    fn new(cfg: &'a Config, secrets: HashMap<String, String>, remap: Option<&'a Remap>) -> Self {
+        let text_sender = if cfg.type_by_character {
+            TextSender::new()
+                .inspect_err(|e| eprintln!("{e}, typing by physical key instead"))
+                .ok()
+        } else {
+            None
+        };
+        let device_state = device_query::DeviceState::new();
+        let (x, y) = device_state.query_pointer().coords;
+        let screen = rdevin::display_size()
+            .ok()
+            .map(|(w, h)| (w as f64, h as f64));
+        Player {
+            cfg,
+            failsafe: &cfg.failsafe,
+            device_state,
+            input: listener::subscribe(),
+            recent_keys: KeySequence::default(),
+            screen,
+            last_pos: (x as f64, y as f64),
+            held_keys: Vec::new(),
+            held_buttons: Vec::new(),
+            path: PathGenerator::new(&cfg.mouse_path),
+            secrets,
+            remap,
+            text_sender,
+            typed_keys: Vec::new(),
+            deferred_keys: Vec::new(),
+        }
+    }
+
+    fn play(&mut self, ev: &Event, dx: f64, dy: f64) -> Result<(), Abort> {
+        self.check()?;
+        let remapped;
+        let ev = match self.remap {
+            Some(remap) => {
+                remapped = ev.remapped(remap);
+                &remapped
+            }
+            None => ev,
+        };
+        match ev {
+            Event::Wait(ms) => return self.wait(*ms),
+            Event::Secret { name } => {
+                match &mut self.text_sender {
+                    Some(sender) => sender.send(&self.secrets[name]),
+                    None => typing::type_text(&self.secrets[name]),
+                }
+                return Ok(());
+            }
+            Event::Label { .. } => return Ok(()),
+            Event::KeyPress(stroke) => {
+                if let (Some(sender), Some(text)) = (&mut self.text_sender, &stroke.text) {
+                    sender.send(text);
+                    if !self.typed_keys.contains(&stroke.key) {
+                        self.typed_keys.push(stroke.key);
+                    }
+                    return Ok(());
+                }
+                if self.text_sender.is_some() && typing::is_shift_key(stroke.key) {
+                    // the text already has it, only pressed once something else needs it
+                    if !self.deferred_keys.contains(&stroke.key) {
+                        self.deferred_keys.push(stroke.key);
+                    }
+                    return Ok(());
+                }
+                self.press_deferred();
+                self.held_keys.push(stroke.key);
+            }
+            Event::KeyRelease(key) => {
+                if let Some(i) = self.typed_keys.iter().position(|k| k == key) {
+                    self.typed_keys.remove(i);
+                    return Ok(());
+                }
+                if let Some(i) = self.deferred_keys.iter().position(|k| k == key) {
+                    self.deferred_keys.remove(i);
+                    return Ok(());
+                }
+                self.held_keys.retain(|k| k != key)
+            }
+            Event::MousePress(m) => {
+                self.press_deferred();
+                self.move_along_path((m.x + dx, m.y + dy))?;
+                self.last_pos = (m.x + dx, m.y + dy);
+                self.held_buttons.push(m.button);
+            }
+            Event::MouseRelease(m) => {
+                self.last_pos = (m.x + dx, m.y + dy);
+                self.held_buttons.retain(|b| *b != m.button);
+            }
+            Event::MouseMove(m) => self.last_pos = (m.x + dx, m.y + dy),
+        }
         ev.simulate_with_offset(dx, dy);
+        Ok(())
+    }
+
+    // press the shift keys held back while typing by character, something other than text
+    // needs them
+    fn press_deferred(&mut self) {
+        for key in self.deferred_keys.drain(..) {
+            let _ = rdevin::simulate(&EventType::KeyPress(key));
+            thread::sleep(Duration::from_millis(1));
+            self.held_keys.push(key);
+        }
+    }
+
+    fn wait(&mut self, ms: u64) -> Result<(), Abort> {
+        let mut remaining = ms;
+        while remaining > 0 {
+            let step = remaining.min(FAILSAFE_POLL_MS);
+            thread::sleep(Duration::from_millis(step));
+            remaining -= step;
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    // glide the pointer towards a press target, if mouse paths are configured
+    fn move_along_path(&mut self, to: (f64, f64)) -> Result<(), Abort> {
+        let points = self.path.path(self.pointer(), to);
+        if points.is_empty() {
+            return Ok(());
+        }
+        let step_ms = self.cfg.mouse_path.duration_ms / (points.len() as u64 + 1);
+        for (x, y) in points {
+            let _ = rdevin::simulate(&EventType::MouseMove { x, y });
+            self.last_pos = (x, y);
+            thread::sleep(Duration::from_millis(step_ms));
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    fn pointer(&self) -> (f64, f64) {
+        let (x, y) = self.device_state.query_pointer().coords;
+        (x as f64, y as f64)
+    }
+
+    fn check(&mut self) -> Result<(), Abort> {
+        if STOP_REQUESTED.swap(false, Ordering::SeqCst) {
+            return Err(Abort::Stopped);
+        }
+        if self.pressed(&self.cfg.pause_keystrokes) {
+            self.pause()?;
+        }
+        if !self.failsafe.enabled {
+            return Ok(());
+        }
+        let mut pos = self.pointer();
+
+        if self.failsafe.mouse_moved_px > 0.0 && self.is_moved(pos) {
+            // the OS may not have caught up with the last simulated move yet
+            thread::sleep(Duration::from_millis(FAILSAFE_POLL_MS));
+            pos = self.pointer();
+            if self.is_moved(pos) {
+                return Err(Abort::MouseMoved { x: pos.0, y: pos.1 });
+            }
+        }
+
+        if let Some((w, h)) = self.screen {
+            let size = self.failsafe.corner_size_px;
+            for corner in &self.failsafe.corners {
+                // playback itself is allowed to click into a corner
+                let placed_there = corner.contains(self.last_pos.0, self.last_pos.1, w, h, size);
+                if !placed_there && corner.contains(pos.0, pos.1, w, h, size) {
+                    return Err(Abort::Corner(*corner));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // whether `keys` were pressed in sequence since the last call
+    fn pressed(&mut self, keys: &[Key]) -> bool {
+        let mut pressed = false;
+        while let Ok(event) = self.input.try_recv() {
+            if let EventType::KeyPress(key) = event.event_type {
+                self.recent_keys.push(key);
+                if self.recent_keys.ends_with(keys) {
+                    self.recent_keys.clear();
+                    pressed = true;
+                }
+            }
+        }
+        pressed
+    }
+
+    // let go of everything until the resume keystrokes are pressed, then put it all back
+    fn pause(&mut self) -> Result<(), Abort> {
+        println!(
+            "playback paused, press {} to resume",
+            listener::describe_keys(&self.cfg.resume_keystrokes)
+        );
+        let keys = self.held_keys.clone();
+        let buttons = self.held_buttons.clone();
+        self.release_held();
+        while !self.pressed(&self.cfg.resume_keystrokes) {
+            if STOP_REQUESTED.swap(false, Ordering::SeqCst) {
+                return Err(Abort::Stopped);
+            }
+            thread::sleep(Duration::from_millis(FAILSAFE_POLL_MS));
+        }
+
+        let (x, y) = self.last_pos;
+        let _ = rdevin::simulate(&EventType::MouseMove { x, y });
+        thread::sleep(Duration::from_millis(1));
+        for key in keys {
+            let _ = rdevin::simulate(&EventType::KeyPress(key));
+            self.held_keys.push(key);
+        }
+        for button in buttons {
+            let _ = rdevin::simulate(&EventType::ButtonPress(button));
+            self.held_buttons.push(button);
+        }
+        println!("playback resumed");
+        Ok(())
+    }
+
+    fn is_moved(&self, (x, y): (f64, f64)) -> bool {
+        let (lx, ly) = self.last_pos;
+        ((x - lx).powi(2) + (y - ly).powi(2)).sqrt() > self.failsafe.mouse_moved_px
+    }
+
+    fn release_held(&mut self) {
+        for key in self.held_keys.drain(..).rev() {
+            let _ = rdevin::simulate(&rdevin::EventType::KeyRelease(key));
+        }
+        for button in self.held_buttons.drain(..).rev() {
+            let _ = rdevin::simulate(&rdevin::EventType::ButtonRelease(button));
+        }
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Event {
-    KeyPress(rdevin::Key),
-    KeyRelease(rdevin::Key),
+    KeyPress(KeyStroke),
+    KeyRelease(#[schemars(with = "KeyName")] rdevin::Key),
     MousePress(MouseEventButton),
     MouseRelease(MouseEventButton),
     MouseMove(MouseEventMove),
     /// wait in milliseconds
     Wait(u64),
+    /// text typed in private mode, its value is looked up at playback and never stored
+    Secret {
+        name: String,
+    },
+    /// a named marker dropped while recording, ignored by playback
+    Label {
+        name: String,
+    },
 }
 
+/// A pressed key, along with the text it produced while recording
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "KeyStrokeRepr", into = "KeyStrokeRepr")]
+pub struct KeyStroke {
+    pub key: rdevin::Key,
+    pub text: Option<String>,
+}
+
+// a bare `key_press = "KeyA"` without text, as macros were written before text was recorded
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+#[serde(untagged)]
+enum KeyStrokeRepr {
+    Key(#[schemars(with = "KeyName")] rdevin::Key),
+    WithText {
+        #[schemars(with = "KeyName")]
+        key: rdevin::Key,
+        text: String,
+    },
+}
+
+impl schemars::JsonSchema for KeyStroke {
+    fn schema_name() -> Cow<'static, str> {
+        "KeyStroke".into()
+    }
+
+    fn json_schema(generator: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        <KeyStrokeRepr as schemars::JsonSchema>::json_schema(generator)
+    }
+}
+
+impl From<KeyStrokeRepr> for KeyStroke {
+    fn from(repr: KeyStrokeRepr) -> Self {
+        match repr {
+            KeyStrokeRepr::Key(key) => KeyStroke { key, text: None },
+            KeyStrokeRepr::WithText { key, text } => KeyStroke {
+                key,
+                text: Some(text),
+            },
+        }
+    }
+}
+
+impl From<KeyStroke> for KeyStrokeRepr {
+    fn from(stroke: KeyStroke) -> Self {
+        match stroke.text {
+            Some(text) => KeyStrokeRepr::WithText {
+                key: stroke.key,
+                text,
+            },
+            None => KeyStrokeRepr::Key(stroke.key),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
 pub struct MouseEventButton {
     pub x: f64,
     pub y: f64,
+    #[schemars(with = "ButtonName")]
     pub button: rdevin::Button,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, Debug, Clone, PartialEq)]
 pub struct MouseEventMove {
     pub x: f64,
     pub y: f64,
 }
 
 impl Event {
-    pub fn simulate(&self) {
-        self.simulate_with_offset(0.0, 0.0);
+    /// The event with its key replaced according to the remap table
+    pub fn remapped(&self, remap: &Remap) -> Event {
+        match self {
+            Event::KeyPress(stroke) => Event::KeyPress(KeyStroke {
+                key: remap.apply(stroke.key),
+                text: stroke.text.clone(),
+            }),
+            Event::KeyRelease(key) => Event::KeyRelease(remap.apply(*key)),
+            other => other.clone(),
+        }
     }
 
     pub fn simulate_with_offset(&self, dx: f64, dy: f64) {
         match self {
-            Event::KeyPress(key) => {
-                let ev_type = rdevin::EventType::KeyPress(*key);
+            Event::KeyPress(stroke) => {
+                let ev_type = rdevin::EventType::KeyPress(stroke.key);
                 rdevin::simulate(&ev_type).unwrap();
             }
             Event::KeyRelease(key) => {
@@ -299,6 +1341,75 @@ impl Event {
                 rdevin::simulate(&ev_type).unwrap();
             }
             Event::Wait(ms) => std::thread::sleep(std::time::Duration::from_millis(*ms)),
+            // only the player knows its value
+            Event::Secret { .. } => {}
+            Event::Label { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::SystemTime};
+
+    fn input(event_type: EventType, name: Option<&str>) -> rdevin::Event {
+        rdevin::Event {
+            time: SystemTime::now(),
+            name: name.map(str::to_string),
+            event_type,
         }
     }
+
+    fn secret(name: &str) -> Event {
+        Event::Secret {
+            name: name.to_string(),
+        }
+    }
+
+    fn tap(key: Key) -> [Event; 2] {
+        [
+            Event::KeyPress(KeyStroke { key, text: None }),
+            Event::KeyRelease(key),
+        ]
+    }
+
+    #[test]
+    fn private_typing_gets_a_secret_per_field() {
+        let cfg = Config::default();
+        let mut recorder =
+            Recorder::starting_at(&cfg, Journal::disabled(), String::new(), (0.0, 0.0));
+        recorder.private = true;
+        // user<Tab>pw<Backspace>d<Enter>
+        let keys = [
+            (Key::KeyU, "u"),
+            (Key::KeyS, "s"),
+            (Key::Tab, "\t"),
+            (Key::KeyP, "p"),
+            (Key::KeyW, "w"),
+            (Key::Backspace, "\u{8}"),
+            (Key::KeyD, "d"),
+            (Key::Return, "\r"),
+        ];
+        for (key, name) in keys {
+            recorder.handle(input(EventType::KeyPress(key), Some(name)));
+            recorder.handle(input(EventType::KeyRelease(key), None));
+        }
+
+        let events: Vec<Event> = recorder
+            .mcro
+            .events
+            .into_iter()
+            .filter(|ev| !matches!(ev, Event::Wait(_)))
+            .collect();
+        let expected: Vec<Event> = [
+            vec![secret("secret-1")],
+            tap(Key::Tab).to_vec(),
+            vec![secret("secret-2")],
+            tap(Key::Backspace).to_vec(),
+            vec![secret("secret-3")],
+            tap(Key::Return).to_vec(),
+        ]
+        .concat();
+        assert_eq!(events, expected);
+    }
 }
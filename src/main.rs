@@ -1,12 +1,29 @@
+mod binary;
 mod config;
+mod daemon;
+mod document;
+mod expand;
+mod instance;
+mod journal;
+mod listener;
 mod macors;
+mod migrate;
+mod path;
+mod schema;
+mod script;
+mod secrets;
+mod server;
+mod simplify;
+mod storage;
+mod typing;
 
 use {
-    crate::config::Config,
+    crate::{config::Config, instance::InstanceLock},
     anyhow::Error,
-    clap::{Parser, Subcommand},
+    clap::{Args, Parser, Subcommand},
     macors::*,
     std::{collections::HashMap, env, fs, process, thread, time::Duration},
+    storage::{Format, list_macros, load_macro, write_macro},
 };
 
 #[derive(Parser)]
@@ -16,12 +33,52 @@ struct Cli {
     command: Commands,
 }
 
+/// Playback settings which can be overridden per run
+#[derive(Args, Debug, Clone)]
+struct PlaybackArgs {
+    /// Glide the pointer to every mouse press along this curve, overrides the setting
+    #[arg(long, value_name = "CURVE")]
+    mouse_path: Option<config::Curve>,
+
+    /// Seed for the mouse path randomness, the same seed plays the same paths
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Type key presses by the character they produced while recording instead of by
+    /// physical key, for playing on a different keyboard layout
+    #[arg(long)]
+    by_char: bool,
+
+    /// Replace keys according to this remap table from the settings, e.g. linux-to-mac
+    #[arg(long, value_name = "PROFILE")]
+    remap: Option<String>,
+}
+
+impl PlaybackArgs {
+    // the settings with these overrides applied
+    fn apply(&self, cfg: &Config) -> Config {
+        let mut cfg = cfg.clone();
+        if let Some(curve) = self.mouse_path {
+            cfg.mouse_path.curve = curve;
+        }
+        cfg.mouse_path.seed = self.seed.or(cfg.mouse_path.seed);
+        cfg.type_by_character |= self.by_char;
+        cfg.remap = self.remap.clone().or(cfg.remap);
+        cfg
+    }
+}
+
 #[derive(Subcommand, Debug, Clone)]
 enum Commands {
     /// Starts recording a macro
     Rec {
         /// Name of the macro to record
-        name: String,
+        #[arg(required_unless_present = "recover")]
+        name: Option<String>,
+
+        /// Turn the journals of interrupted recordings into macros, instead of recording
+        #[arg(long, conflicts_with = "name")]
+        recover: bool,
 
         /// Add a description to the macro
         #[arg(short, long, default_value = "add a description")]
@@ -30,6 +87,72 @@ enum Commands {
         /// Allow overwriting existing macro
         #[arg(short, long)]
         overwrite: bool,
+
+        /// Add the recording to the end of the existing macro
+        #[arg(long, conflicts_with_all = ["overwrite", "insert_at"])]
+        append: bool,
+
+        /// Splice the recording into the existing macro, before the selected event (and the
+        /// wait leading up to it), e.g. mouse_press.Left:3rd
+        #[arg(long, value_name = "ACTION", conflicts_with = "overwrite")]
+        insert_at: Option<String>,
+
+        /// Run even if another macors instance is already playing or recording
+        #[arg(long)]
+        force: bool,
+
+        /// Never record this key, on top of the configured record filter (repeatable)
+        #[arg(long = "ignore-key", value_name = "KEY", value_parser = config::parse_key)]
+        ignore_keys: Vec<rdevin::Key>,
+
+        /// Leave out mouse events inside this screen rectangle
+        #[arg(long, value_name = "X,Y,W,H")]
+        ignore_inside: Option<config::Rect>,
+
+        /// Leave out mouse events outside this screen rectangle
+        #[arg(long, value_name = "X,Y,W,H")]
+        ignore_outside: Option<config::Rect>,
+
+        /// Only record the keyboard
+        #[arg(long, conflicts_with = "mouse_only")]
+        keyboard_only: bool,
+
+        /// Only record the mouse
+        #[arg(long)]
+        mouse_only: bool,
+
+        /// Record at most one mouse move per this many milliseconds, overrides the setting
+        #[arg(long, value_name = "MS")]
+        sample_ms: Option<u64>,
+
+        /// Stop and save after recording for this many seconds
+        #[arg(long, value_name = "SECS")]
+        max_duration: Option<u64>,
+
+        /// Stop and save once this many events (not counting waits) are recorded
+        #[arg(long, value_name = "N")]
+        max_events: Option<usize>,
+
+        /// Stop and save after this many seconds without any input
+        #[arg(long, value_name = "SECS")]
+        idle_timeout: Option<u64>,
+    },
+    /// Replays a macro up to an event, then records a replacement for a range of its events
+    Rerecord {
+        /// Name of the macro to fix
+        name: String,
+
+        /// First event to replace, e.g. mouse_press.Left:19th
+        #[arg(long, value_name = "ACTION")]
+        from: String,
+
+        /// Last event to replace, defaults to the --from event
+        #[arg(long, value_name = "ACTION")]
+        to: Option<String>,
+
+        /// Run even if another macors instance is already playing or recording
+        #[arg(long)]
+        force: bool,
     },
     /// Runs a recorded macro
     Run {
@@ -43,6 +166,18 @@ enum Commands {
         /// Optional action selector to run only a specific event (e.g. mouse_press.Left:19th)
         #[arg(short = 'a', long = "action", value_name = "ACTION")]
         action: Option<String>,
+
+        /// Instead of counting down, wait for the arm keystrokes and start playback when they
+        /// are pressed. Re-arms after every run until interrupted with Ctrl+C.
+        #[arg(long)]
+        arm: bool,
+
+        /// Run even if another macors instance is already playing or recording
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        playback: PlaybackArgs,
     },
     /// Runs a recorded macro relative to current mouse position
     RunOffset {
@@ -52,6 +187,36 @@ enum Commands {
         /// Number of times to repeat the macro
         #[arg(short = 'n', long = "repeat", default_value_t = 1)]
         repeat: usize,
+
+        /// Run even if another macors instance is already playing or recording
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        playback: PlaybackArgs,
+    },
+    /// Ask the running macors instance to stop its playback or recording
+    Stop,
+    /// Show what the running macors instance is doing
+    Status,
+    /// Listen globally and play macros bound to key sequences in the settings
+    Daemon {
+        /// Only list the configured bindings and exit
+        #[arg(short, long)]
+        list: bool,
+    },
+    /// Watch typed keys and replace abbreviations from expansions.toml with their expansion
+    Expand {
+        /// Only list the configured expansions and exit
+        #[arg(short, long)]
+        list: bool,
+    },
+    /// Listen on a local socket for JSON-lines requests from other processes
+    Serve,
+    /// Send a single JSON request to `macors serve` and print the responses
+    Client {
+        /// The request, e.g. '{"cmd": "run", "name": "mymacro", "repeat": 2}'
+        request: String,
     },
     /// List all recorded macros
     Ls,
@@ -73,7 +238,7 @@ enum Commands {
     },
     /// Edit a recorded macro using $EDITOR
     Edit {
-        /// Name of the macro to edit (without .toml)
+        /// Name of the macro to edit (without extension)
         name: String,
         /// Optional action selector (positional), e.g. mouse_press.Left:19th
         #[arg(value_name = "ACTION", conflicts_with = "action_flag")]
@@ -87,6 +252,67 @@ enum Commands {
         )]
         action_flag: Option<String>,
     },
+    /// List the labels in a macro, or rename one
+    Label {
+        /// Name of the macro
+        name: String,
+        /// Label to rename
+        #[arg(requires = "new_name")]
+        label: Option<String>,
+        /// New name for the label
+        new_name: Option<String>,
+    },
+    /// Split a macro into parts at its labels, or at the selected events
+    Split {
+        /// Name of the macro to split, it is left as it is
+        name: String,
+        /// Split before this event instead of at every label (repeatable), e.g. label.form_opened
+        #[arg(long = "at", value_name = "ACTION")]
+        at: Vec<String>,
+        /// Allow overwriting existing macros with the parts
+        #[arg(short, long)]
+        overwrite: bool,
+    },
+    /// Replace keys in a macro for good, according to a remap table from the settings
+    Remap {
+        /// Name of the macro to rewrite
+        name: String,
+        /// Remap table to apply, e.g. linux-to-mac
+        profile: String,
+    },
+    /// Upgrade macros written by an older macors to the current format version
+    Migrate {
+        /// Name of the macro to migrate
+        #[arg(required_unless_present = "all")]
+        name: Option<String>,
+        /// Migrate every macro
+        #[arg(long, conflicts_with = "name")]
+        all: bool,
+        /// Only print what would be migrated
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Drop mouse moves that barely change the path, merging their waits
+    Simplify {
+        /// Name of the macro to simplify
+        name: String,
+        /// Furthest a dropped move may lie from the simplified path, in pixels
+        #[arg(short, long, value_name = "PX", default_value_t = 2.0)]
+        tolerance: f64,
+    },
+    /// Print the JSON Schema of macro or settings files, for editor validation and completion
+    Schema {
+        #[arg(value_enum)]
+        target: schema::Target,
+    },
+    /// Store a macro in another file format
+    Convert {
+        /// Name of the macro to convert
+        name: String,
+        /// Format to store it in
+        #[arg(value_enum)]
+        format: Format,
+    },
     /// Clone a click group with an offset
     Clone {
         /// Name of the macro to edit
@@ -111,19 +337,93 @@ fn main() -> Result<(), Error> {
     match &cli.command {
         Commands::Rec {
             name,
+            recover,
             desc,
             overwrite,
+            append,
+            insert_at,
+            force,
+            ignore_keys,
+            ignore_inside,
+            ignore_outside,
+            keyboard_only,
+            mouse_only,
+            sample_ms,
+            max_duration,
+            max_events,
+            idle_timeout,
         } => {
-            if !*overwrite {
+            if *recover {
+                let _lock = match InstanceLock::acquire(&command_line(), *force) {
+                    Ok(lock) => lock,
+                    Err(e) => {
+                        eprintln!("{e}");
+                        return Ok(());
+                    }
+                };
+                let journals = journal::interrupted();
+                if journals.is_empty() {
+                    println!("no interrupted recordings");
+                }
+                for path in journals {
+                    match journal::recover(&path, cfg.default_format) {
+                        Ok(saved) => println!("recovered {saved}"),
+                        Err(e) => eprintln!("{e}"),
+                    }
+                }
+                return Ok(());
+            }
+            let Some(name) = name else {
+                eprintln!("a macro name is required");
+                return Ok(());
+            };
+            let splice = if *append || insert_at.is_some() {
+                let base = match load_macro(name) {
+                    Ok(mcro) => mcro,
+                    Err(e) => {
+                        eprintln!("{e}");
+                        return Ok(());
+                    }
+                };
+                let at = match insert_at {
+                    Some(raw_action) => match select_event(&base.events, raw_action) {
+                        // take the wait leading up to the event along with it
+                        Ok(idx) if idx > 0 && matches!(base.events[idx - 1], Event::Wait(_)) => {
+                            idx - 1
+                        }
+                        Ok(idx) => idx,
+                        Err(e) => {
+                            eprintln!("{e}");
+                            return Ok(());
+                        }
+                    },
+                    None => base.events.len(),
+                };
+                Some(Splice {
+                    base,
+                    range: at..at,
+                })
+            } else {
+                None
+            };
+
+            if !*overwrite && splice.is_none() {
                 // if overwrite is not set, check if file exists and prevent overwriting
-                let macros_dir = config::macros_path();
-                let file_path = macros_dir.join(format!("{}.toml", name));
-                if file_path.exists() {
+                if storage::exists(name) {
                     eprintln!("macro \"{name}\" already exists, use --overwrite to overwrite");
                     return Ok(());
                 }
             }
 
+            let _lock = match InstanceLock::acquire(&command_line(), *force) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+            journal::prompt_recovery(cfg.default_format);
+
             let secs = cfg.countdown_seconds;
             println!(
                 "Beginning recording, default mapping for ending the recording is Esc+Esc+Esc"
@@ -141,28 +441,132 @@ fn main() -> Result<(), Error> {
                 a_bit_more_than_a_second_and_a_half_ms,
             )
             .unwrap();
-            record(&cfg, name.to_string(), desc.to_string());
+            let mut cfg = cfg.clone();
+            let filter = &mut cfg.record_filter;
+            filter.ignore_keys.extend_from_slice(ignore_keys);
+            filter.ignore_inside = ignore_inside.or(filter.ignore_inside);
+            filter.ignore_outside = ignore_outside.or(filter.ignore_outside);
+            filter.keyboard_only |= *keyboard_only;
+            filter.mouse_only |= *mouse_only;
+            if let Some(ms) = sample_ms {
+                cfg.mouse_move_sample_ms = *ms;
+            }
+            let limits = &mut cfg.record_limits;
+            limits.max_duration_secs = max_duration.or(limits.max_duration_secs);
+            limits.max_events = max_events.or(limits.max_events);
+            limits.idle_timeout_secs = idle_timeout.or(limits.idle_timeout_secs);
+            record(&cfg, name.to_string(), desc.to_string(), splice);
+        }
+        Commands::Rerecord {
+            name,
+            from,
+            to,
+            force,
+        } => {
+            let base = match load_macro(name) {
+                Ok(mcro) => mcro,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+            let range = match select_event(&base.events, from).and_then(|start| {
+                let end = match to {
+                    Some(to) => {
+                        let end = select_event(&base.events, to)?;
+                        if end < start {
+                            return Err(format!("{to} comes before {from}"));
+                        }
+                        end
+                    }
+                    None => start,
+                };
+                // replace the wait leading up to the first event as well
+                if start > 0 && matches!(base.events[start - 1], Event::Wait(_)) {
+                    Ok(start - 1..end + 1)
+                } else {
+                    Ok(start..end + 1)
+                }
+            }) {
+                Ok(range) => range,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+
+            let _lock = match InstanceLock::acquire(&command_line(), *force) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+
+            let backup = format!("{name}.bak");
+            if let Err(e) = storage::copy_macro(name, &backup) {
+                eprintln!("{e}");
+                return Ok(());
+            }
+            println!("original kept as {backup}");
+
+            println!(
+                "Replaying {} events of {name}, recording starts right after",
+                range.start
+            );
+            let secs = cfg.countdown_seconds;
+            println!("Playback starts in...");
+            for i in (1..=secs).rev() {
+                println!("{}...", i);
+                thread::sleep(Duration::from_millis(950));
+            }
+            if let Err(abort) = play_events(&cfg, &base.events[..range.start], 0.0, 0.0) {
+                eprintln!("playback aborted, {abort}");
+                return Ok(());
+            }
+            // let the replayed events make their way through the hook, or the recording would
+            // pick them up
+            listener::settle(&listener::subscribe());
+
+            println!("Recording, default mapping for ending the recording is Esc+Esc+Esc");
+            let middle_e_hz = 329;
+            let a_bit_more_than_a_second_and_a_half_ms = 100;
+            actually_beep::beep_with_hz_and_millis(
+                middle_e_hz,
+                a_bit_more_than_a_second_and_a_half_ms,
+            )
+            .unwrap();
+            let description = base.description.clone();
+            record(
+                &cfg,
+                name.to_string(),
+                description,
+                Some(Splice { base, range }),
+            );
         }
-        Commands::Run { name, repeat, action } => {
-            let macros_dir = config::macros_path();
-            let file_path = macros_dir.join(format!("{}.toml", name));
-            if !file_path.exists() {
+        Commands::Run {
+            name,
+            repeat,
+            action,
+            arm,
+            force,
+            playback,
+        } => {
+            let cfg = playback.apply(&cfg);
+            if !storage::exists(name) {
                 eprintln!("macro \"{name}\" not found");
                 return Ok(());
             }
+            if *arm && cfg.arm_keystrokes.is_empty() {
+                eprintln!("--arm needs arm_keystrokes in the settings, e.g. [\"F9\"]");
+                return Ok(());
+            }
 
             let action_event = if let Some(raw_action) = action.clone() {
-                let contents = match fs::read_to_string(&file_path) {
-                    Ok(c) => c,
-                    Err(e) => {
-                        eprintln!("Failed to read macro file: {e}");
-                        return Ok(());
-                    }
-                };
-                let events: Macro = match toml::from_str(&contents) {
+                let events = match load_macro(name) {
                     Ok(evs) => evs,
                     Err(e) => {
-                        eprintln!("Failed to deserialize macro file: {e:?}");
+                        eprintln!("{e}");
                         return Ok(());
                     }
                 };
@@ -190,6 +594,49 @@ fn main() -> Result<(), Error> {
                 None
             };
 
+            let lock = match InstanceLock::acquire(&command_line(), *force) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+
+            let run = || {
+                lock.start_run();
+                for _ in 0..*repeat {
+                    let res = if let Some(ev) = &action_event {
+                        play_events(&cfg, std::slice::from_ref(ev), 0.0, 0.0)
+                    } else {
+                        start_playback(&cfg, name)
+                    };
+                    if let Err(abort) = res {
+                        eprintln!("playback aborted, {abort}");
+                        break;
+                    }
+                }
+            };
+
+            if *arm {
+                let keys = listener::describe_keys(&cfg.arm_keystrokes);
+                let rx = listener::subscribe();
+                loop {
+                    println!("Armed, press {keys} to start playback (Ctrl+C to quit)");
+                    listener::wait_for_sequence(&rx, &cfg.arm_keystrokes);
+                    println!("Begin!");
+                    let middle_e_hz = 329;
+                    let a_bit_more_than_a_second_and_a_half_ms = 100;
+                    actually_beep::beep_with_hz_and_millis(
+                        middle_e_hz,
+                        a_bit_more_than_a_second_and_a_half_ms,
+                    )
+                    .unwrap();
+                    run();
+                    // don't let keys typed by the macro itself re-trigger it
+                    listener::settle(&rx);
+                }
+            }
+
             // Countdown and start beep consistent with full playback
             let secs = cfg.countdown_seconds;
             println!("Playback starts in...");
@@ -205,22 +652,28 @@ fn main() -> Result<(), Error> {
                 a_bit_more_than_a_second_and_a_half_ms,
             )
             .unwrap();
-            for _ in 0..*repeat {
-                if let Some(ev) = &action_event {
-                    ev.simulate();
-                } else {
-                    start_playback(&cfg, name);
-                }
-            }
+            run();
         }
-        Commands::RunOffset { name, repeat } => {
-            let macros_dir = config::macros_path();
-            let file_path = macros_dir.join(format!("{}.toml", name));
-            if !file_path.exists() {
+        Commands::RunOffset {
+            name,
+            repeat,
+            force,
+            playback,
+        } => {
+            let cfg = playback.apply(&cfg);
+            if !storage::exists(name) {
                 eprintln!("macro \"{name}\" not found");
                 return Ok(());
             }
 
+            let _lock = match InstanceLock::acquire(&command_line(), *force) {
+                Ok(lock) => lock,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+
             println!(
                 "Running macro (offset mode): {} for {} time(s)",
                 name, repeat
@@ -242,69 +695,65 @@ fn main() -> Result<(), Error> {
             )
             .unwrap();
             for _ in 0..*repeat {
-                start_playback_with_offset(&cfg, name);
+                if let Err(abort) = start_playback_with_offset(&cfg, name) {
+                    eprintln!("playback aborted, {abort}");
+                    break;
+                }
             }
         }
-        Commands::Ls => {
-            let macros_dir = config::macros_path();
-            // write all files in the directory to stdout but not the toml extension
-            for entry in fs::read_dir(macros_dir).expect("Failed to read macros directory") {
-                let entry = entry.expect("Failed to read macros directory entry");
-                let path = entry.path();
-                if path.is_file() && path.extension().is_some() {
-                    // get the description from the toml file
-                    let contents = fs::read_to_string(&path).expect("Failed to read file");
-                    let evs: Macro = match toml::from_str(&contents) {
-                        Ok(evs) => evs,
-                        Err(e) => {
-                            println!("Failed to deserialize macro file: {:?}", e);
-                            return Ok(());
-                        }
-                    };
-                    let description = evs.description;
-                    let name = path
-                        .file_stem()
-                        .expect("Failed to get file stem")
-                        .to_str()
-                        .expect("Failed to convert file stem to str");
-
-                    print!("{name:<27} - ");
-
-                    // print description with line breaks
-                    let mut lines = description.lines();
-                    if let Some(first_line) = lines.next() {
-                        println!("{}", first_line); // Print the first line after the first field
-                    }
-                    for line in lines {
-                        println!("{:<30}{line}", "");
-                    }
-                }
+        Commands::Stop => instance::stop()?,
+        Commands::Status => instance::status(),
+        Commands::Daemon { list } => {
+            if *list {
+                daemon::list_bindings(&cfg);
+            } else {
+                daemon::run(cfg);
             }
         }
-        Commands::Rm { name } => {
-            let macros_dir = config::macros_path();
-            let file_path = macros_dir.join(format!("{}.toml", name));
-            if !file_path.exists() {
-                eprintln!("macro \"{name}\" not found");
-                return Ok(());
+        Commands::Expand { list } => {
+            if *list {
+                expand::Expansions::load()?.list();
+            } else {
+                expand::run(&cfg)?;
             }
-            fs::remove_file(&file_path).expect("Failed to remove existing macro file");
         }
-        Commands::Show { name, stat, all } => {
-            let macros_dir = config::macros_path();
-            let file_path = macros_dir.join(format!("{}.toml", name));
-            let contents = match fs::read_to_string(&file_path) {
-                Ok(c) => c,
-                Err(_) => {
-                    eprintln!("macro \"{name}\" not found");
+        Commands::Serve => server::serve(cfg)?,
+        Commands::Client { request } => server::client(request)?,
+        Commands::Ls => {
+            let macros = match list_macros() {
+                Ok(macros) => macros,
+                Err(e) => {
+                    println!("{e}");
                     return Ok(());
                 }
             };
+            for (name, description) in macros {
+                print!("{name:<27} - ");
 
-            let evs: Macro = match toml::from_str(&contents) {
+                // print description with line breaks
+                let mut lines = description.lines();
+                if let Some(first_line) = lines.next() {
+                    println!("{}", first_line); // Print the first line after the first field
+                }
+                for line in lines {
+                    println!("{:<30}{line}", "");
+                }
+            }
+        }
+        Commands::Rm { name } => match storage::remove_macro(name) {
+            Ok(true) => {}
+            Ok(false) => eprintln!("macro \"{name}\" not found"),
+            Err(e) => eprintln!("{e}"),
+        },
+        Commands::Show { name, stat, all } => {
+            if !storage::exists(name) {
+                eprintln!("macro \"{name}\" not found");
+                return Ok(());
+            }
+            let evs = match load_macro(name) {
                 Ok(evs) => evs,
                 Err(e) => {
-                    eprintln!("Failed to deserialize macro file: {e:?}");
+                    eprintln!("{e}");
                     return Ok(());
                 }
             };
@@ -370,12 +819,15 @@ fn main() -> Result<(), Error> {
                 }
             };
 
-            let macros_dir = config::macros_path();
-            fs::create_dir_all(&macros_dir).expect("Failed to ensure macros directory exists");
-            let file_path = macros_dir.join(format!("{}.toml", name));
-            if !file_path.exists() {
+            let Some((file_path, format)) = storage::find(name) else {
                 eprintln!("macro \"{name}\" not found");
                 return Ok(());
+            };
+            if !format.is_text() {
+                eprintln!(
+                    "{name} is stored as {format}, convert it to toml, json or script to edit it"
+                );
+                return Ok(());
             }
 
             let selected_action = action_flag.clone().or(action.clone());
@@ -389,10 +841,10 @@ fn main() -> Result<(), Error> {
                     }
                 };
 
-                let events: Macro = match toml::from_str(&contents) {
+                let events = match format.deserialize(contents.as_bytes()) {
                     Ok(evs) => evs,
                     Err(e) => {
-                        eprintln!("Failed to deserialize macro file: {e:?}");
+                        eprintln!("{e}");
                         return Ok(());
                     }
                 };
@@ -416,7 +868,7 @@ fn main() -> Result<(), Error> {
                     return Ok(());
                 };
 
-                let op_line = event_start_line(&contents, event_idx);
+                let op_line = format.event_line(&contents, event_idx);
                 let Some(line_num) = op_line else {
                     eprintln!("Could not locate event position in file for action {raw_action}");
                     return Ok(());
@@ -444,20 +896,14 @@ fn main() -> Result<(), Error> {
             offset_x,
             offset_y,
         } => {
-            let macros_dir = config::macros_path();
-            let file_path = macros_dir.join(format!("{}.toml", name));
-            let contents = match fs::read_to_string(&file_path) {
-                Ok(c) => c,
-                Err(_) => {
-                    eprintln!("macro \"{name}\" not found");
-                    return Ok(());
-                }
-            };
-
-            let mut mcro: Macro = match toml::from_str(&contents) {
+            if !storage::exists(name) {
+                eprintln!("macro \"{name}\" not found");
+                return Ok(());
+            }
+            let mut mcro = match load_macro(name) {
                 Ok(evs) => evs,
                 Err(e) => {
-                    eprintln!("Failed to deserialize macro file: {e:?}");
+                    eprintln!("{e}");
                     return Ok(());
                 }
             };
@@ -498,16 +944,8 @@ fn main() -> Result<(), Error> {
                 return Ok(());
             }
 
-            let toml_string = match toml::to_string(&mcro) {
-                Ok(s) => s,
-                Err(e) => {
-                    eprintln!("Failed to serialize macro: {e}");
-                    return Ok(());
-                }
-            };
-
-            if let Err(e) = fs::write(&file_path, toml_string) {
-                eprintln!("Failed to write macro file: {e}");
+            if let Err(e) = write_macro(name, &mcro, cfg.default_format) {
+                eprintln!("{e}");
                 return Ok(());
             }
 
@@ -521,10 +959,259 @@ fn main() -> Result<(), Error> {
                 offset_y
             );
         }
+        Commands::Label {
+            name,
+            label,
+            new_name,
+        } => {
+            let mut mcro = match load_macro(name) {
+                Ok(mcro) => mcro,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+            let (Some(label), Some(new_name)) = (label, new_name) else {
+                for (idx, ev) in mcro.events.iter().enumerate() {
+                    if let Event::Label { name } = ev {
+                        println!("{idx}: {name}");
+                    }
+                }
+                return Ok(());
+            };
+            if new_name.is_empty() || new_name.contains(['.', ':']) {
+                eprintln!("label names can't be empty or contain '.' or ':'");
+                return Ok(());
+            }
+            let mut labels = mcro.events.iter();
+            if labels.any(|ev| matches!(ev, Event::Label { name } if name == new_name)) {
+                eprintln!("label \"{new_name}\" already exists");
+                return Ok(());
+            }
+            let renamed = mcro.events.iter_mut().find_map(|ev| match ev {
+                Event::Label { name } if name == label => Some(name),
+                _ => None,
+            });
+            let Some(renamed) = renamed else {
+                eprintln!("label \"{label}\" not found");
+                return Ok(());
+            };
+            *renamed = new_name.clone();
+            if let Err(e) = write_macro(name, &mcro, cfg.default_format) {
+                eprintln!("{e}");
+                return Ok(());
+            }
+            println!("Renamed label {label} to {new_name}");
+        }
+        Commands::Split {
+            name,
+            at,
+            overwrite,
+        } => {
+            let mcro = match load_macro(name) {
+                Ok(mcro) => mcro,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+            let mut splits = Vec::new();
+            if at.is_empty() {
+                for (idx, ev) in mcro.events.iter().enumerate() {
+                    if matches!(ev, Event::Label { .. }) {
+                        splits.push(idx);
+                    }
+                }
+            }
+            for raw_action in at {
+                match select_event(&mcro.events, raw_action) {
+                    Ok(idx) => splits.push(idx),
+                    Err(e) => {
+                        eprintln!("{e}");
+                        return Ok(());
+                    }
+                }
+            }
+            // split before the wait leading up to the event as well
+            for idx in &mut splits {
+                if *idx > 0 && matches!(mcro.events[*idx - 1], Event::Wait(_)) {
+                    *idx -= 1;
+                }
+            }
+            splits.retain(|idx| *idx > 0);
+            splits.sort();
+            splits.dedup();
+            if splits.is_empty() {
+                eprintln!("nothing to split {name} at");
+                return Ok(());
+            }
+
+            let mut bounds = vec![0];
+            bounds.extend(splits);
+            bounds.push(mcro.events.len());
+            let parts: Vec<(String, Macro)> = bounds
+                .windows(2)
+                .enumerate()
+                .map(|(i, w)| {
+                    let events = mcro.events[w[0]..w[1]].to_vec();
+                    let label = events.iter().find(|e| !matches!(e, Event::Wait(_)));
+                    let part_name = match label {
+                        Some(Event::Label { name: label }) => format!("{name}-{label}"),
+                        _ => format!("{name}-{}", i + 1),
+                    };
+                    let part = Macro {
+                        version: migrate::CURRENT_VERSION,
+                        description: format!("{} (part {})", mcro.description, i + 1),
+                        ended_by: None,
+                        events,
+                    };
+                    (part_name, part)
+                })
+                .collect();
+
+            if !*overwrite {
+                for (part_name, _) in &parts {
+                    if storage::exists(part_name) {
+                        eprintln!(
+                            "macro \"{part_name}\" already exists, use --overwrite to overwrite"
+                        );
+                        return Ok(());
+                    }
+                }
+            }
+            let format = storage::find(name).map_or(cfg.default_format, |(_, f)| f);
+            for (part_name, part) in &parts {
+                if let Err(e) = write_macro(part_name, part, format) {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+                println!("Saved {part_name} ({} events)", part.events.len());
+            }
+        }
+        Commands::Remap { name, profile } => {
+            let remap = match cfg.remap_profile(profile) {
+                Ok(remap) => remap,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+            let mut mcro = match load_macro(name) {
+                Ok(mcro) => mcro,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+            let mut changed = 0;
+            for ev in &mut mcro.events {
+                let remapped = ev.remapped(remap);
+                if remapped != *ev {
+                    *ev = remapped;
+                    changed += 1;
+                }
+            }
+            if let Err(e) = write_macro(name, &mcro, cfg.default_format) {
+                eprintln!("{e}");
+                return Ok(());
+            }
+            println!("Remapped {changed} key events in {name} with {profile}");
+        }
+        Commands::Simplify { name, tolerance } => {
+            let mut mcro = match load_macro(name) {
+                Ok(mcro) => mcro,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+            let before = mcro.events.len();
+            mcro.events = simplify::simplify(&mcro.events, *tolerance);
+            if let Err(e) = write_macro(name, &mcro, cfg.default_format) {
+                eprintln!("{e}");
+                return Ok(());
+            }
+            println!(
+                "Removed {} of {before} events from {name}",
+                before - mcro.events.len()
+            );
+        }
+        Commands::Migrate { name, all, dry_run } => {
+            let names = match name {
+                Some(name) => vec![name.clone()],
+                None => match storage::macro_names() {
+                    Ok(names) => names,
+                    Err(e) => {
+                        eprintln!("{e}");
+                        return Ok(());
+                    }
+                },
+            };
+            for name in names {
+                let (version, format) = match storage::version_of(&name) {
+                    Ok(found) => found,
+                    Err(e) => {
+                        eprintln!("{name}: {e}");
+                        continue;
+                    }
+                };
+                if let Err(e) = migrate::check(version) {
+                    eprintln!("{name}: {e}");
+                    continue;
+                }
+                let steps = migrate::pending(version);
+                if steps.is_empty() {
+                    if !all {
+                        println!("{name} is up to date");
+                    }
+                    continue;
+                }
+                let summary: Vec<&str> = steps.iter().map(|step| step.summary).collect();
+                let summary = summary.join(", ");
+                if *dry_run {
+                    println!("would migrate {name} from version {version}: {summary}");
+                    continue;
+                }
+                let result = load_macro(&name)
+                    .and_then(|mcro| storage::write_macro_as(&name, &mcro, format));
+                match result {
+                    Ok(()) => println!("migrated {name} from version {version}: {summary}"),
+                    Err(e) => eprintln!("{name}: {e}"),
+                }
+            }
+        }
+        Commands::Schema { target } => println!("{}", schema::generate(*target)),
+        Commands::Convert { name, format } => {
+            let Some((_, current)) = storage::find(name) else {
+                eprintln!("macro \"{name}\" not found");
+                return Ok(());
+            };
+            if current == *format {
+                println!("{name} is already stored as {format}");
+                return Ok(());
+            }
+            let mcro = match load_macro(name) {
+                Ok(mcro) => mcro,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return Ok(());
+                }
+            };
+            if let Err(e) = storage::write_macro_as(name, &mcro, *format) {
+                eprintln!("{e}");
+                return Ok(());
+            }
+            println!("Converted {name} from {current} to {format}");
+        }
     }
     Ok(())
 }
 
+// the arguments macors was started with, for the lock file
+fn command_line() -> String {
+    env::args().skip(1).collect::<Vec<_>>().join(" ")
+}
+
 #[derive(Debug, Clone)]
 struct ActionSelector {
     selector: EventSelector,
@@ -539,6 +1226,7 @@ enum EventSelector {
     Wait,
     KeyPress(Option<String>),
     KeyRelease(Option<String>),
+    Label(Option<String>),
 }
 
 fn describe_event(ev: &Event) -> String {
@@ -553,8 +1241,13 @@ fn describe_event(ev: &Event) -> String {
             m.button, m.x as i64, m.y as i64
         ),
         Event::MouseMove(m) => format!("mouse_move to ({}, {})", m.x as i64, m.y as i64),
-        Event::KeyPress(k) => format!("key_press {:?}", k),
+        Event::KeyPress(k) => match &k.text {
+            Some(text) => format!("key_press {:?} {text:?}", k.key),
+            None => format!("key_press {:?}", k.key),
+        },
         Event::KeyRelease(k) => format!("key_release {:?}", k),
+        Event::Secret { name } => format!("secret {name} (typed at playback)"),
+        Event::Label { name } => format!("label {name}"),
     }
 }
 
@@ -655,8 +1348,10 @@ fn stat_label(ev: &Event) -> String {
         Event::MouseMove(_) => "mouse_move".to_string(),
         Event::MousePress(m) => format!("mouse_press.{:?}", m.button),
         Event::MouseRelease(m) => format!("mouse_release.{:?}", m.button),
-        Event::KeyPress(k) => format!("key_press.{:?}", k),
+        Event::KeyPress(k) => format!("key_press.{:?}", k.key),
         Event::KeyRelease(k) => format!("key_release.{:?}", k),
+        Event::Secret { .. } => "secret".to_string(),
+        Event::Label { .. } => "label".to_string(),
     }
 }
 
@@ -665,9 +1360,8 @@ fn parse_action(raw: &str) -> Result<ActionSelector, String> {
     let head = parts
         .next()
         .ok_or_else(|| "missing selector before ':'".to_string())?;
-    let ord_raw = parts
-        .next()
-        .ok_or_else(|| "missing ordinal after ':'".to_string())?;
+    // without an ordinal the first match is meant, e.g. label.form_opened
+    let ord_raw = parts.next().unwrap_or("1st");
     if parts.next().is_some() {
         return Err("too many ':' segments".into());
     }
@@ -693,12 +1387,23 @@ fn parse_action(raw: &str) -> Result<ActionSelector, String> {
         "wait" => EventSelector::Wait,
         "key_press" => EventSelector::KeyPress(detail),
         "key_release" => EventSelector::KeyRelease(detail),
+        "label" => EventSelector::Label(detail),
         other => return Err(format!("unsupported event kind: {other}")),
     };
 
     Ok(ActionSelector { selector, ordinal })
 }
 
+// the index of the event an action selector picks out
+fn select_event(events: &[Event], raw_action: &str) -> Result<usize, String> {
+    let selector = parse_action(raw_action).map_err(|e| format!("Invalid action: {e}"))?;
+    if selector.ordinal == 0 {
+        return Err("Ordinal must be 1 or greater".to_string());
+    }
+    find_event_index(events, &selector)
+        .ok_or_else(|| format!("No matching event found for action {raw_action}"))
+}
+
 fn find_event_index(events: &[Event], selector: &ActionSelector) -> Option<usize> {
     let mut seen = 0usize;
     for (idx, ev) in events.iter().enumerate() {
@@ -725,9 +1430,12 @@ fn matches_selector(ev: &Event, selector: &EventSelector) -> bool {
             None => true,
         },
         (EventSelector::KeyPress(key_name), Event::KeyPress(k)) => match key_name {
-            Some(kname) => key_eq(kname, k),
+            Some(kname) => key_eq(kname, &k.key),
             None => true,
         },
+        (EventSelector::Label(label), Event::Label { name }) => {
+            label.as_ref().is_none_or(|label| label == name)
+        }
         (EventSelector::KeyRelease(key_name), Event::KeyRelease(k)) => match key_name {
             Some(kname) => key_eq(kname, k),
             None => true,
@@ -743,16 +1451,3 @@ fn button_eq(name: &str, button: &rdevin::Button) -> bool {
 fn key_eq(name: &str, key: &rdevin::Key) -> bool {
     name.eq_ignore_ascii_case(&format!("{:?}", key))
 }
-
-fn event_start_line(contents: &str, zero_based_index: usize) -> Option<usize> {
-    let mut idx = 0usize;
-    for (line_no, line) in contents.lines().enumerate() {
-        if line.trim() == "[[events]]" {
-            if idx == zero_based_index {
-                return Some(line_no + 1); // 1-based for editors
-            }
-            idx += 1;
-        }
-    }
-    None
-}
@@ -0,0 +1,50 @@
+use serde_json::Value;
+
+/// The macro format version written by this build, `MIGRATIONS` says what changed when.
+pub const CURRENT_VERSION: u32 = 1;
+
+/// A step taking a macro document from one version to the next
+pub struct Migration {
+    pub summary: &'static str,
+    apply: fn(&mut Value) -> Result<(), String>,
+}
+
+// MIGRATIONS[v] upgrades a version v document to version v + 1, add a step here whenever a
+// change to `Macro` or `Event` would stop older files from loading
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [
+    // macros from before they were versioned are otherwise the same as version 1
+    Migration {
+        summary: "record the format version",
+        apply: |_| Ok(()),
+    },
+];
+
+/// Fails for a macro written by a newer macors, which this one can't be trusted to read
+pub fn check(version: u32) -> Result<(), String> {
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "the macro was written by a newer macors (format version {version}, this one reads \
+             up to {CURRENT_VERSION}), upgrade macors to use it"
+        ));
+    }
+    Ok(())
+}
+
+/// The steps taking a macro of this version to the current one
+pub fn pending(version: u32) -> &'static [Migration] {
+    &MIGRATIONS[(version.min(CURRENT_VERSION) as usize)..]
+}
+
+/// Upgrade a macro document of the given version to the current one
+pub fn upgrade(doc: &mut Value, version: u32) -> Result<(), String> {
+    check(version)?;
+    for (from, step) in (version..).zip(pending(version)) {
+        (step.apply)(doc)
+            .map_err(|e| format!("Failed to migrate macro from version {from}: {e}"))?;
+    }
+    let Some(table) = doc.as_object_mut() else {
+        return Err("macro file doesn't hold a table".to_string());
+    };
+    table.insert("version".to_string(), CURRENT_VERSION.into());
+    Ok(())
+}
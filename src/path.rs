@@ -0,0 +1,98 @@
+use {
+    crate::config::{Curve, MousePath},
+    std::time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Generates the intermediate pointer positions of synthesized mouse paths
+pub struct PathGenerator {
+    curve: Curve,
+    steps: u32,
+    rng: Rng,
+}
+
+impl PathGenerator {
+    pub fn new(cfg: &MousePath) -> Self {
+        let seed = cfg.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        });
+        PathGenerator {
+            curve: cfg.curve,
+            steps: cfg.steps.max(1),
+            rng: Rng(seed),
+        }
+    }
+
+    /// The points leading from `from` up to, but not including, `to`. Empty when the curve is
+    /// `none` or the pointer is already there.
+    pub fn path(&mut self, from: (f64, f64), to: (f64, f64)) -> Vec<(f64, f64)> {
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let dist = (dx * dx + dy * dy).sqrt();
+        if self.curve == Curve::None || dist < 1.0 {
+            return Vec::new();
+        }
+
+        // control points for the bezier curve, pushed sideways off the straight line
+        let (c1, c2) = if self.curve == Curve::Bezier {
+            let (nx, ny) = (-dy / dist, dx / dist);
+            let mut control = |along: f64| {
+                let bend = (self.rng.next_f64() - 0.5) * dist * 0.6;
+                (
+                    from.0 + dx * along + nx * bend,
+                    from.1 + dy * along + ny * bend,
+                )
+            };
+            (control(1.0 / 3.0), control(2.0 / 3.0))
+        } else {
+            (from, to)
+        };
+
+        (1..self.steps)
+            .map(|i| {
+                let t = i as f64 / self.steps as f64;
+                match self.curve {
+                    Curve::None | Curve::Linear => lerp(from, to, t),
+                    Curve::Eased => lerp(from, to, ease(t)),
+                    Curve::Bezier => bezier(from, c1, c2, to, ease(t)),
+                }
+            })
+            .collect()
+    }
+}
+
+fn lerp(a: (f64, f64), b: (f64, f64), t: f64) -> (f64, f64) {
+    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+}
+
+// ease in and out, slow at both ends
+fn ease(t: f64) -> f64 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn bezier(p0: (f64, f64), p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), t: f64) -> (f64, f64) {
+    let u = 1.0 - t;
+    let (a, b, c, d) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+    (
+        a * p0.0 + b * p1.0 + c * p2.0 + d * p3.0,
+        a * p0.1 + b * p1.1 + c * p2.1 + d * p3.1,
+    )
+}
+
+// splitmix64, plenty for wobbling a mouse path and reproducible across platforms
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
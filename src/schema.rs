@@ -0,0 +1,116 @@
+use {
+    crate::{config::Config, macors::Macro},
+    schemars::{JsonSchema, Schema, SchemaGenerator, json_schema, schema_for},
+    serde::de::{self, DeserializeOwned, Deserializer, Visitor},
+    serde_json::{Map, Value},
+    std::borrow::Cow,
+};
+
+/// The files a schema can be exported for
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Target {
+    /// macro files, `macros/<name>.toml` or `.json`
+    Macro,
+    /// settings.toml
+    Settings,
+}
+
+/// JSON Schema for macro or settings files, for editors to validate and complete them with
+pub fn generate(target: Target) -> String {
+    let schema = match target {
+        Target::Macro => schema_for!(Macro),
+        Target::Settings => schema_for!(Config),
+    };
+    serde_json::to_string_pretty(&schema).expect("Failed to serialize schema")
+}
+
+/// Stands in for `rdevin::Key` in schemas
+pub struct KeyName;
+
+impl JsonSchema for KeyName {
+    fn schema_name() -> Cow<'static, str> {
+        "Key".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        enum_schema::<rdevin::Key>("A key, by its name in rdevin")
+    }
+}
+
+/// Stands in for `rdevin::Button` in schemas
+pub struct ButtonName;
+
+impl JsonSchema for ButtonName {
+    fn schema_name() -> Cow<'static, str> {
+        "Button".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        enum_schema::<rdevin::Button>("A mouse button, by its name in rdevin")
+    }
+}
+
+// every variant of a foreign enum, unit ones as plain strings and those carrying data (like
+// `Unknown(u32)`) as single key tables
+fn enum_schema<T: DeserializeOwned>(description: &str) -> Schema {
+    let (names, tagged): (Vec<&str>, Vec<&str>) = variants::<T>()
+        .iter()
+        .partition(|name| serde_json::from_value::<T>(Value::String(name.to_string())).is_ok());
+    if tagged.is_empty() {
+        return json_schema!({
+            "description": description,
+            "type": "string",
+            "enum": names,
+        });
+    }
+    let properties: Map<String, Value> = tagged
+        .iter()
+        .map(|name| (name.to_string(), Value::Bool(true)))
+        .collect();
+    json_schema!({
+        "description": description,
+        "oneOf": [
+            { "type": "string", "enum": names },
+            {
+                "type": "object",
+                "properties": properties,
+                "additionalProperties": false,
+                "minProperties": 1,
+                "maxProperties": 1,
+            },
+        ],
+    })
+}
+
+// the variant names derived `Deserialize` impls hand to `deserialize_enum`
+fn variants<T: DeserializeOwned>() -> &'static [&'static str] {
+    let mut names: &'static [&'static str] = &[];
+    let _ = T::deserialize(VariantNames(&mut names));
+    names
+}
+
+struct VariantNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for VariantNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not an enum"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = variants;
+        Err(de::Error::custom("only the variant names are wanted"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
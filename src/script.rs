@@ -0,0 +1,917 @@
+use {
+    crate::{
+        config,
+        macors::{Event, KeyStroke, Macro, MouseEventButton, MouseEventMove},
+        migrate,
+        typing::char_to_key,
+    },
+    rdevin::{Button, Key},
+    std::fmt,
+};
+
+// A line oriented format for writing macros by hand, e.g.
+//
+//     description "Save and close"
+//     click left 100,200
+//     type "hello"
+//     wait 250ms
+//     key ctrl+s
+//     repeat 3 {
+//         key tab
+//     }
+//
+// Every statement stands for a fixed run of events, so printing a macro and parsing it back
+// gives exactly the same events. Anything after a `#` outside a string is a comment.
+const INDENT: &str = "    ";
+// the longest run of lines looked for when folding repeats into a `repeat` block
+const MAX_REPEAT_LEN: usize = 32;
+// guards against a mistyped repeat count filling up memory
+const MAX_EVENTS: usize = 1 << 24;
+
+// short names for common keys, any other key goes by its name in rdevin, e.g. `ControlRight`
+const KEY_ALIASES: [(&str, Key); 18] = [
+    ("ctrl", Key::ControlLeft),
+    ("shift", Key::ShiftLeft),
+    ("alt", Key::Alt),
+    ("meta", Key::MetaLeft),
+    ("enter", Key::Return),
+    ("esc", Key::Escape),
+    ("tab", Key::Tab),
+    ("space", Key::Space),
+    ("backspace", Key::Backspace),
+    ("delete", Key::Delete),
+    ("up", Key::UpArrow),
+    ("down", Key::DownArrow),
+    ("left", Key::LeftArrow),
+    ("right", Key::RightArrow),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("pageup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+];
+
+/// Parse a script into a macro, errors say on which line and column they are. The macro keeps
+/// the version it was written with, `Format::deserialize` upgrades older ones
+pub fn parse(contents: &str) -> Result<Macro, String> {
+    parse_with_lines(contents)
+        .map(|(mcro, _)| mcro)
+        .map_err(|e| e.to_string())
+}
+
+/// The 1-based line on which the event with the given index is written, events repeated by a
+/// `repeat` block point at the line inside it
+pub fn event_line(contents: &str, index: usize) -> Option<usize> {
+    let (_, lines) = parse_with_lines(contents).ok()?;
+    lines.get(index).copied()
+}
+
+/// The macro as a script, folding events into `click`, `type`, `key` and `repeat` wherever
+/// that reads back the same
+pub fn print(mcro: &Macro) -> String {
+    let mut out = format!("version {}\n", mcro.version);
+    out.push_str(&format!("description {}\n", quote(&mcro.description)));
+    if let Some(ended_by) = &mcro.ended_by {
+        out.push_str(&format!("ended_by {}\n", quote(ended_by)));
+    }
+    out.push('\n');
+    write_lines(&mut out, &fold_repeats(&statements(&mcro.events)), 0);
+    out
+}
+
+struct Error {
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+fn error(line: usize, column: usize, message: impl Into<String>) -> Error {
+    Error {
+        line,
+        column,
+        message: message.into(),
+    }
+}
+
+/// A word or string on a line, columns count characters from 1
+struct Token {
+    text: String,
+    column: usize,
+    quoted: bool,
+}
+
+enum Statement {
+    Version(u32),
+    Description(String),
+    EndedBy(String),
+    Events(Vec<Event>),
+    Repeat(usize),
+    End,
+}
+
+/// The events of a `repeat` block, or of the whole script, as they are parsed
+struct Block {
+    count: usize,
+    line: usize,
+    column: usize,
+    events: Vec<Event>,
+    lines: Vec<usize>,
+}
+
+impl Block {
+    fn new(count: usize, line: usize, column: usize) -> Self {
+        Block {
+            count,
+            line,
+            column,
+            events: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+}
+
+// the macro, and the line every event of it was written on
+fn parse_with_lines(contents: &str) -> Result<(Macro, Vec<usize>), Error> {
+    let mut mcro = Macro::default();
+    let mut blocks = vec![Block::new(1, 1, 1)];
+    for (i, text) in contents.lines().enumerate() {
+        let line = i + 1;
+        let tokens = tokenize(text, line)?;
+        let Some(first) = tokens.first() else {
+            continue;
+        };
+        let nested = blocks.len() > 1;
+        let block = blocks.last_mut().expect("the script itself is always open");
+        match statement(&tokens, line)? {
+            Statement::Version(_) | Statement::Description(_) | Statement::EndedBy(_) if nested => {
+                return Err(error(
+                    line,
+                    first.column,
+                    format!("`{}` can't be inside a `repeat` block", first.text),
+                ));
+            }
+            Statement::Version(version) => mcro.version = version,
+            Statement::Description(description) => mcro.description = description,
+            Statement::EndedBy(ended_by) => mcro.ended_by = Some(ended_by),
+            Statement::Events(events) => {
+                block.lines.extend(events.iter().map(|_| line));
+                block.events.extend(events);
+            }
+            Statement::Repeat(count) => blocks.push(Block::new(count, line, first.column)),
+            Statement::End => {
+                if !nested {
+                    return Err(error(line, first.column, "`}` without a `repeat` to close"));
+                }
+                let block = blocks.pop().expect("checked above");
+                let parent = blocks.last_mut().expect("checked above");
+                let total = block
+                    .events
+                    .len()
+                    .checked_mul(block.count)
+                    .and_then(|n| n.checked_add(parent.events.len()))
+                    .filter(|n| *n <= MAX_EVENTS);
+                if total.is_none() {
+                    return Err(error(
+                        block.line,
+                        block.column,
+                        format!("repeating this makes more than {MAX_EVENTS} events"),
+                    ));
+                }
+                for _ in 0..block.count {
+                    parent.events.extend_from_slice(&block.events);
+                    parent.lines.extend_from_slice(&block.lines);
+                }
+            }
+        }
+    }
+    let block = blocks.pop().expect("the script itself is always open");
+    if !blocks.is_empty() {
+        return Err(error(
+            block.line,
+            block.column,
+            "`repeat` block is never closed with `}`",
+        ));
+    }
+    mcro.events = block.events;
+    Ok((mcro, block.lines))
+}
+
+// split a line into words and strings, dropping any comment
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().zip(1..).peekable();
+    while let Some(&(c, column)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '#' {
+            break;
+        }
+        let mut word = String::new();
+        let quoted = c == '"';
+        if quoted {
+            chars.next();
+            loop {
+                match chars.next() {
+                    None => return Err(error(line, column, "string is never closed with `\"`")),
+                    Some(('"', _)) => break,
+                    Some(('\\', at)) => word.push(escape(&mut chars, line, at)?),
+                    Some((c, _)) => word.push(c),
+                }
+            }
+        } else {
+            while let Some(&(c, _)) = chars.peek() {
+                if c.is_whitespace() || c == '"' || c == '#' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+        }
+        tokens.push(Token {
+            text: word,
+            column,
+            quoted,
+        });
+    }
+    Ok(tokens)
+}
+
+// the character an escape stands for, the backslash at `column` has been read already
+fn escape(
+    chars: &mut impl Iterator<Item = (char, usize)>,
+    line: usize,
+    column: usize,
+) -> Result<char, Error> {
+    let invalid = || {
+        error(
+            line,
+            column,
+            "invalid escape, expected one of \\\" \\\\ \\n \\t \\r \\0 \\u{…}",
+        )
+    };
+    let c = match chars.next() {
+        Some(('"', _)) => '"',
+        Some(('\\', _)) => '\\',
+        Some(('n', _)) => '\n',
+        Some(('t', _)) => '\t',
+        Some(('r', _)) => '\r',
+        Some(('0', _)) => '\0',
+        Some(('u', _)) => {
+            if chars.next().map(|(c, _)| c) != Some('{') {
+                return Err(invalid());
+            }
+            let mut hex = String::new();
+            loop {
+                match chars.next() {
+                    Some(('}', _)) => break,
+                    Some((c, _)) if c.is_ascii_hexdigit() && hex.len() < 6 => hex.push(c),
+                    _ => return Err(invalid()),
+                }
+            }
+            u32::from_str_radix(&hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(invalid)?
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(c)
+}
+
+fn statement(tokens: &[Token], line: usize) -> Result<Statement, Error> {
+    let (command, args) = tokens.split_first().expect("blank lines are skipped");
+    let usage = |usage: &str| error(line, command.column, format!("expected `{usage}`"));
+    if command.quoted {
+        return Err(error(
+            line,
+            command.column,
+            "expected a statement, not a string",
+        ));
+    }
+    let statement = match (command.text.as_str(), args) {
+        ("version", [v]) => {
+            let version = number(v, line)?;
+            migrate::check(version).map_err(|e| error(line, v.column, e))?;
+            Statement::Version(version)
+        }
+        ("version", _) => return Err(usage("version <number>")),
+        ("description", [text]) => Statement::Description(string(text, line)?),
+        ("description", _) => return Err(usage("description \"text\"")),
+        ("ended_by", [text]) => Statement::EndedBy(string(text, line)?),
+        ("ended_by", _) => return Err(usage("ended_by \"text\"")),
+        ("press", [key]) => Statement::Events(vec![Event::KeyPress(KeyStroke {
+            key: parse_key(key, line)?,
+            text: None,
+        })]),
+        ("press", [key, text]) => Statement::Events(vec![Event::KeyPress(KeyStroke {
+            key: parse_key(key, line)?,
+            text: Some(string(text, line)?),
+        })]),
+        ("press", _) => return Err(usage("press <key> [\"text\"]")),
+        ("release", [key]) => Statement::Events(vec![Event::KeyRelease(parse_key(key, line)?)]),
+        ("release", _) => return Err(usage("release <key>")),
+        ("key", [chord]) => {
+            let mut keys = Vec::new();
+            let mut column = chord.column;
+            for name in word(chord, line)?.split('+') {
+                let token = Token {
+                    text: name.to_string(),
+                    column,
+                    quoted: false,
+                };
+                keys.push(parse_key(&token, line)?);
+                column += name.chars().count() + 1;
+            }
+            Statement::Events(chord_events(&keys))
+        }
+        ("key", _) => return Err(usage("key <key>[+<key>…]")),
+        ("type", [text]) => {
+            let mut events = Vec::new();
+            for c in string(text, line)?.chars() {
+                let Some(typed) = typed_events(c) else {
+                    return Err(error(
+                        line,
+                        text.column,
+                        format!("{c:?} can't be typed, use `press <key> \"text\"` for it"),
+                    ));
+                };
+                events.extend(typed);
+            }
+            Statement::Events(events)
+        }
+        ("type", _) => return Err(usage("type \"text\"")),
+        ("mouse-down", [button, pos]) => {
+            Statement::Events(vec![Event::MousePress(mouse_button(button, pos, line)?)])
+        }
+        ("mouse-down", _) => return Err(usage("mouse-down <button> <x>,<y>")),
+        ("mouse-up", [button, pos]) => {
+            Statement::Events(vec![Event::MouseRelease(mouse_button(button, pos, line)?)])
+        }
+        ("mouse-up", _) => return Err(usage("mouse-up <button> <x>,<y>")),
+        ("click", [button, pos]) => {
+            Statement::Events(click_events(mouse_button(button, pos, line)?))
+        }
+        ("click", _) => return Err(usage("click <button> <x>,<y>")),
+        ("move", [pos]) => {
+            let (x, y) = coords(pos, line)?;
+            Statement::Events(vec![Event::MouseMove(MouseEventMove { x, y })])
+        }
+        ("move", _) => return Err(usage("move <x>,<y>")),
+        ("wait", [duration]) => Statement::Events(vec![Event::Wait(wait_ms(duration, line)?)]),
+        ("wait", _) => return Err(usage("wait <ms>ms")),
+        ("secret", [name]) => Statement::Events(vec![Event::Secret {
+            name: string(name, line)?,
+        }]),
+        ("secret", _) => return Err(usage("secret \"name\"")),
+        ("label", [name]) => Statement::Events(vec![Event::Label {
+            name: string(name, line)?,
+        }]),
+        ("label", _) => return Err(usage("label \"name\"")),
+        ("repeat", [count, open]) if open.text == "{" && !open.quoted => {
+            Statement::Repeat(number(count, line)?)
+        }
+        ("repeat", _) => return Err(usage("repeat <count> {")),
+        ("}", []) => Statement::End,
+        ("}", [extra, ..]) => {
+            return Err(error(line, extra.column, "`}` goes on a line of its own"));
+        }
+        (unknown, _) => {
+            return Err(error(
+                line,
+                command.column,
+                format!("unknown statement `{unknown}`"),
+            ));
+        }
+    };
+    Ok(statement)
+}
+
+// the text of a string argument, bare words are turned down so the grammar stays unambiguous
+fn string(token: &Token, line: usize) -> Result<String, Error> {
+    if !token.quoted {
+        return Err(error(
+            line,
+            token.column,
+            format!("expected a quoted string, e.g. \"{}\"", token.text),
+        ));
+    }
+    Ok(token.text.clone())
+}
+
+// the text of a bare word, keys, buttons, numbers and the like are never quoted
+fn word(token: &Token, line: usize) -> Result<&str, Error> {
+    if token.quoted {
+        return Err(error(
+            line,
+            token.column,
+            "expected a word, not a quoted string",
+        ));
+    }
+    Ok(&token.text)
+}
+
+fn number<T: std::str::FromStr>(token: &Token, line: usize) -> Result<T, Error> {
+    word(token, line)?.parse().map_err(|_| {
+        error(
+            line,
+            token.column,
+            format!("invalid number `{}`", token.text),
+        )
+    })
+}
+
+fn wait_ms(token: &Token, line: usize) -> Result<u64, Error> {
+    let text = word(token, line)?;
+    let ms = if let Some(ms) = text.strip_suffix("ms") {
+        ms.parse().ok()
+    } else if let Some(secs) = text.strip_suffix('s') {
+        secs.parse::<u64>().ok().and_then(|s| s.checked_mul(1000))
+    } else {
+        None
+    };
+    ms.ok_or_else(|| {
+        error(
+            line,
+            token.column,
+            format!(
+                "invalid duration `{}`, expected e.g. 250ms or 2s",
+                token.text
+            ),
+        )
+    })
+}
+
+fn coords(token: &Token, line: usize) -> Result<(f64, f64), Error> {
+    let parsed = word(token, line)?
+        .split_once(',')
+        .and_then(|(x, y)| Some((x.parse().ok()?, y.parse().ok()?)));
+    parsed.ok_or_else(|| {
+        error(
+            line,
+            token.column,
+            format!("invalid position `{}`, expected <x>,<y>", token.text),
+        )
+    })
+}
+
+fn mouse_button(button: &Token, pos: &Token, line: usize) -> Result<MouseEventButton, Error> {
+    let (x, y) = coords(pos, line)?;
+    let button = match word(button, line)? {
+        "left" => Button::Left,
+        "right" => Button::Right,
+        "middle" => Button::Middle,
+        name => unknown_code(name).map(Button::Unknown).ok_or_else(|| {
+            error(
+                line,
+                button.column,
+                format!("unknown button `{name}`, expected left, right or middle"),
+            )
+        })?,
+    };
+    Ok(MouseEventButton { x, y, button })
+}
+
+fn button_name(button: Button) -> String {
+    match button {
+        Button::Left => "left".to_string(),
+        Button::Right => "right".to_string(),
+        Button::Middle => "middle".to_string(),
+        Button::Unknown(code) => format!("Unknown({code})"),
+    }
+}
+
+fn parse_key(token: &Token, line: usize) -> Result<Key, Error> {
+    let name = word(token, line)?;
+    if let Some((_, key)) = KEY_ALIASES.iter().find(|(alias, _)| *alias == name) {
+        return Ok(*key);
+    }
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next())
+        && (c.is_ascii_lowercase() || c.is_ascii_digit())
+        && let Some((key, _)) = char_to_key(c)
+    {
+        return Ok(key);
+    }
+    if let Some(code) = unknown_code(name) {
+        return Ok(Key::Unknown(code));
+    }
+    config::parse_key(name).map_err(|e| error(line, token.column, e))
+}
+
+fn key_name(key: Key) -> String {
+    if let Some((alias, _)) = KEY_ALIASES.iter().find(|(_, k)| *k == key) {
+        return alias.to_string();
+    }
+    let single = ('a'..='z')
+        .chain('0'..='9')
+        .find(|c| char_to_key(*c).is_some_and(|(k, _)| k == key));
+    match single {
+        Some(c) => c.to_string(),
+        // the same as the serde name, `Unknown(5)` included
+        None => format!("{key:?}"),
+    }
+}
+
+// the code of a key or button without a name of its own, written as `Unknown(<code>)`
+fn unknown_code<T: std::str::FromStr>(name: &str) -> Option<T> {
+    name.strip_prefix("Unknown(")?
+        .strip_suffix(')')?
+        .parse()
+        .ok()
+}
+
+fn chord_events(keys: &[Key]) -> Vec<Event> {
+    let presses = keys.iter().map(|key| {
+        Event::KeyPress(KeyStroke {
+            key: *key,
+            text: None,
+        })
+    });
+    let releases = keys.iter().rev().map(|key| Event::KeyRelease(*key));
+    presses.chain(releases).collect()
+}
+
+// typing a character presses its key with shift held when needed, as it would be recorded
+fn typed_events(c: char) -> Option<Vec<Event>> {
+    let (key, shift) = char_to_key(c)?;
+    let stroke = [
+        Event::KeyPress(KeyStroke {
+            key,
+            text: Some(c.to_string()),
+        }),
+        Event::KeyRelease(key),
+    ];
+    if !shift {
+        return Some(stroke.to_vec());
+    }
+    let mut events = chord_events(&[Key::ShiftLeft]);
+    events.splice(1..1, stroke);
+    Some(events)
+}
+
+fn click_events(m: MouseEventButton) -> Vec<Event> {
+    vec![Event::MousePress(m.clone()), Event::MouseRelease(m)]
+}
+
+// a statement for every run of events, each only ever used if it parses back to that run
+fn statements(events: &[Event]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < events.len() {
+        let rest = &events[i..];
+        let typed = typed_text(rest);
+        if typed.len > 0 {
+            lines.push(format!("type {}", quote(&typed.text)));
+            i += typed.len;
+            continue;
+        }
+        if let Some(keys) = chord(rest) {
+            let names: Vec<String> = keys.iter().map(|key| key_name(*key)).collect();
+            lines.push(format!("key {}", names.join("+")));
+            i += keys.len() * 2;
+            continue;
+        }
+        if let Event::MousePress(m) = &rest[0]
+            && rest.starts_with(&click_events(m.clone()))
+        {
+            lines.push(format!("click {}", mouse_args(m)));
+            i += 2;
+            continue;
+        }
+        lines.push(event_statement(&rest[0]));
+        i += 1;
+    }
+    lines
+}
+
+struct Typed {
+    text: String,
+    len: usize,
+}
+
+// the text typed by the events at the start, and how many events that takes
+fn typed_text(events: &[Event]) -> Typed {
+    let mut typed = Typed {
+        text: String::new(),
+        len: 0,
+    };
+    loop {
+        let rest = &events[typed.len..];
+        let candidate = match rest {
+            [Event::KeyPress(stroke), ..] if stroke.key != Key::ShiftLeft => stroke.text.as_ref(),
+            [_, Event::KeyPress(stroke), ..] => stroke.text.as_ref(),
+            _ => None,
+        };
+        let mut chars = candidate.map(|text| text.chars()).into_iter().flatten();
+        let (Some(c), None) = (chars.next(), chars.next()) else {
+            return typed;
+        };
+        match typed_events(c) {
+            Some(expected) if rest.starts_with(&expected) => {
+                typed.text.push(c);
+                typed.len += expected.len();
+            }
+            _ => return typed,
+        }
+    }
+}
+
+// the keys of a chord pressed at the start, as many as are released again in reverse
+fn chord(events: &[Event]) -> Option<Vec<Key>> {
+    let pressed: Vec<Key> = events
+        .iter()
+        .map_while(|ev| match ev {
+            Event::KeyPress(KeyStroke { key, text: None }) => Some(*key),
+            _ => None,
+        })
+        .collect();
+    (1..=pressed.len())
+        .rev()
+        .map(|n| pressed[..n].to_vec())
+        .find(|keys| events.starts_with(&chord_events(keys)))
+}
+
+fn event_statement(ev: &Event) -> String {
+    match ev {
+        Event::KeyPress(KeyStroke { key, text: None }) => format!("press {}", key_name(*key)),
+        Event::KeyPress(KeyStroke {
+            key,
+            text: Some(text),
+        }) => format!("press {} {}", key_name(*key), quote(text)),
+        Event::KeyRelease(key) => format!("release {}", key_name(*key)),
+        Event::MousePress(m) => format!("mouse-down {}", mouse_args(m)),
+        Event::MouseRelease(m) => format!("mouse-up {}", mouse_args(m)),
+        // `Display` of a float is the shortest text parsing back to exactly it
+        Event::MouseMove(m) => format!("move {},{}", m.x, m.y),
+        Event::Wait(ms) => format!("wait {ms}ms"),
+        Event::Secret { name } => format!("secret {}", quote(name)),
+        Event::Label { name } => format!("label {}", quote(name)),
+    }
+}
+
+fn mouse_args(m: &MouseEventButton) -> String {
+    format!("{} {},{}", button_name(m.button), m.x, m.y)
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+enum Line {
+    Plain(String),
+    Repeat(usize, Vec<Line>),
+}
+
+// fold runs of lines repeating back to back into `repeat` blocks, where that saves lines
+fn fold_repeats(lines: &[String]) -> Vec<Line> {
+    let mut folded = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        // (length, count) of the run saving the most lines, a block spends two on its braces
+        let mut best: Option<(usize, usize)> = None;
+        for len in 1..=MAX_REPEAT_LEN.min((lines.len() - i) / 2) {
+            let body = &lines[i..i + len];
+            let count = 1 + lines[i + len..]
+                .chunks_exact(len)
+                .take_while(|chunk| *chunk == body)
+                .count();
+            let saved = len * (count - 1);
+            if saved > 2 && best.is_none_or(|(l, c)| saved > l * (c - 1)) {
+                best = Some((len, count));
+            }
+        }
+        match best {
+            Some((len, count)) => {
+                folded.push(Line::Repeat(count, fold_repeats(&lines[i..i + len])));
+                i += len * count;
+            }
+            None => {
+                folded.push(Line::Plain(lines[i].clone()));
+                i += 1;
+            }
+        }
+    }
+    folded
+}
+
+fn write_lines(out: &mut String, lines: &[Line], depth: usize) {
+    let indent = INDENT.repeat(depth);
+    for line in lines {
+        match line {
+            Line::Plain(line) => out.push_str(&format!("{indent}{line}\n")),
+            Line::Repeat(count, body) => {
+                out.push_str(&format!("{indent}repeat {count} {{\n"));
+                write_lines(out, body, depth + 1);
+                out.push_str(&format!("{indent}}}\n"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::storage::Format, migrate::CURRENT_VERSION};
+
+    // printing the parsed script gives the script back, and parsing that the same macro
+    fn assert_round_trip(script: &str) -> Macro {
+        let mcro = parse(script).unwrap();
+        let printed = print(&mcro);
+        assert_eq!(printed, script);
+        assert_eq!(parse(&printed).unwrap(), mcro);
+        mcro
+    }
+
+    fn press(key: Key, text: Option<&str>) -> Event {
+        Event::KeyPress(KeyStroke {
+            key,
+            text: text.map(str::to_string),
+        })
+    }
+
+    fn error_at(script: &str) -> (usize, usize) {
+        let Err(e) = parse_with_lines(script) else {
+            panic!("{script:?} should not parse");
+        };
+        (e.line, e.column)
+    }
+
+    #[test]
+    fn round_trip() {
+        let script = format!(
+            "version {CURRENT_VERSION}
+description \"every statement\"
+ended_by \"a \\\"quoted\\\" line\\nand a\\ttab \\u{{7}}\\\\\"
+
+type \"Hello, World!\"
+key ctrl+s
+key ctrl+shift+Unknown(77)
+press a \"a\"
+key Unknown(5)
+press q \"é\"
+release q
+click left 100,200
+click Unknown(9) -0.5,1000000000000000
+mouse-down right 3,4
+move 10.25,-7
+mouse-up right 10.25,-7
+wait 250ms
+secret \"secret-1\"
+label \"label \\\"1\\\"\"
+"
+        );
+        let mcro = assert_round_trip(&script);
+        assert_eq!(
+            mcro.ended_by.as_deref(),
+            Some("a \"quoted\" line\nand a\ttab \u{7}\\")
+        );
+        assert_eq!(
+            mcro.events[..4],
+            [
+                press(Key::ShiftLeft, None),
+                press(Key::KeyH, Some("H")),
+                Event::KeyRelease(Key::KeyH),
+                Event::KeyRelease(Key::ShiftLeft),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_fold_into_statements() {
+        let mut events = Vec::new();
+        for c in "Hi!".chars() {
+            events.extend(typed_events(c).unwrap());
+        }
+        events.extend(chord_events(&[Key::ControlLeft, Key::Unknown(77)]));
+        events.extend(click_events(MouseEventButton {
+            x: 1.0,
+            y: 2.0,
+            button: Button::Unknown(4),
+        }));
+        // a shifted press without the text a typed one has stays a press
+        events.push(press(Key::ShiftLeft, None));
+        events.push(press(Key::KeyA, None));
+        let mcro = Macro {
+            events,
+            ..Macro::default()
+        };
+        let printed = print(&mcro);
+        let body = printed.split_once("\n\n").unwrap().1;
+        assert_eq!(
+            body,
+            "type \"Hi!\"
+key ctrl+Unknown(77)
+click Unknown(4) 1,2
+press shift
+press a
+"
+        );
+        assert_eq!(parse(&printed).unwrap(), mcro);
+    }
+
+    #[test]
+    fn repeats_fold_and_nest() {
+        let script = format!(
+            "version {CURRENT_VERSION}
+description \"\"
+
+repeat 3 {{
+    key tab
+    repeat 4 {{
+        click left 1,2
+    }}
+    type \"x\"
+}}
+wait 5ms
+"
+        );
+        let mcro = assert_round_trip(&script);
+        assert_eq!(mcro.events.len(), 3 * (2 + 4 * 2 + 2) + 1);
+
+        // too few lines are saved to be worth a block
+        let short = "repeat 2 {\n    key tab\n}\n";
+        let printed = print(&parse(short).unwrap());
+        assert!(printed.ends_with("\nkey tab\nkey tab\n"), "{printed}");
+    }
+
+    #[test]
+    fn event_lines() {
+        let script = "description \"x\"\n\nwait 1ms\nrepeat 2 {\n    key tab\n}\n";
+        let lines: Vec<_> = (0..6).map(|i| event_line(script, i)).collect();
+        assert_eq!(lines, [Some(3), Some(5), Some(5), Some(5), Some(5), None]);
+    }
+
+    #[test]
+    fn errors_say_where() {
+        assert_eq!(error_at("click left 100"), (1, 12));
+        assert_eq!(error_at("wait 1ms\n  key ctrl+bogus"), (2, 12));
+        assert_eq!(error_at("wait 1ms\n\n  repeat 2 {\n    key tab\n"), (3, 3));
+        assert_eq!(error_at("}"), (1, 1));
+        assert_eq!(error_at("type \"never closed"), (1, 6));
+        assert_eq!(error_at("type \"bad \\q escape\""), (1, 11));
+        assert_eq!(error_at("repeat 2 {\n    description \"x\"\n}"), (2, 5));
+        assert_eq!(error_at("bogus 1"), (1, 1));
+        assert_eq!(error_at("version 99999"), (1, 9));
+        let message = parse("wait 1ms\nwait soon").unwrap_err();
+        assert!(message.starts_with("line 2, column 6: "), "{message}");
+    }
+
+    #[test]
+    fn quotes_only_where_the_grammar_has_them() {
+        assert_eq!(error_at("press a b"), (1, 9));
+        assert_eq!(error_at("label x"), (1, 7));
+        assert_eq!(error_at("secret x"), (1, 8));
+        assert_eq!(error_at("type hello"), (1, 6));
+        assert_eq!(error_at("description x"), (1, 13));
+        assert_eq!(error_at("repeat 3 \"{\"\n}"), (1, 1));
+        assert_eq!(error_at("key \"ctrl+s\""), (1, 5));
+        assert_eq!(error_at("click \"left\" 1,2"), (1, 7));
+        assert_eq!(error_at("wait \"5ms\""), (1, 6));
+        assert_eq!(error_at("\"wait\" 5ms"), (1, 1));
+        // a `#` in a string is not a comment, a `}` in a string not the end of a block
+        let mcro = parse("repeat 2 {\n    label \"#}\" # a comment\n}").unwrap();
+        assert_eq!(
+            mcro.events,
+            [
+                Event::Label {
+                    name: "#}".to_string()
+                },
+                Event::Label {
+                    name: "#}".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn older_versions_are_upgraded_on_load() {
+        let script = "version 0\ndescription \"old\"\nwait 5ms\n";
+        assert_eq!(parse(script).unwrap().version, 0);
+        let loaded = Format::Script.deserialize(script.as_bytes()).unwrap();
+        assert_eq!(loaded.version, CURRENT_VERSION);
+        assert_eq!(loaded.events, [Event::Wait(5)]);
+    }
+}
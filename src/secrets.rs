@@ -0,0 +1,85 @@
+use {
+    crate::{config, macors::Event},
+    std::{
+        collections::HashMap,
+        env, fs,
+        sync::{Mutex, OnceLock},
+    },
+};
+
+// values typed in at a prompt, so repeated runs only ask once
+static PROMPTED: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// The environment variable holding a secret, e.g. MACORS_SECRET_SECRET_1 for "secret-1"
+pub fn env_var(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("MACORS_SECRET_{name}")
+}
+
+/// Values for every secret placeholder in the events, taken from the environment, the secrets
+/// file or else asked for
+pub fn resolve(events: &[Event]) -> Result<HashMap<String, String>, String> {
+    resolve_names(events.iter().filter_map(|ev| match ev {
+        Event::Secret { name } => Some(name.as_str()),
+        _ => None,
+    }))
+}
+
+/// Like `resolve`, for secrets known by name
+pub fn resolve_names<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<HashMap<String, String>, String> {
+    let mut values = HashMap::new();
+    let mut file: Option<HashMap<String, String>> = None;
+    for name in names {
+        if values.contains_key(name) {
+            continue;
+        }
+        if let Ok(value) = env::var(env_var(name)) {
+            values.insert(name.to_string(), value);
+            continue;
+        }
+        let file = match &mut file {
+            Some(file) => file,
+            None => file.insert(load_file()?),
+        };
+        if let Some(value) = file.get(name) {
+            values.insert(name.to_string(), value.clone());
+            continue;
+        }
+        values.insert(name.to_string(), prompt(name)?);
+    }
+    Ok(values)
+}
+
+// the `name = "value"` pairs in secrets.toml, if there is one
+fn load_file() -> Result<HashMap<String, String>, String> {
+    let path = config::secrets_path();
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return Ok(HashMap::new());
+    };
+    toml::from_str(&contents).map_err(|e| format!("Failed to parse {}: {e}", path.display()))
+}
+
+fn prompt(name: &str) -> Result<String, String> {
+    let prompted = PROMPTED.get_or_init(Default::default);
+    if let Some(value) = prompted.lock().expect("secrets poisoned").get(name) {
+        return Ok(value.clone());
+    }
+    let value = rpassword::prompt_password(format!("value for secret {name}: "))
+        .map_err(|e| format!("no value for secret {name} ({}): {e}", env_var(name)))?;
+    prompted
+        .lock()
+        .expect("secrets poisoned")
+        .insert(name.to_string(), value.clone());
+    Ok(value)
+}
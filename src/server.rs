@@ -0,0 +1,323 @@
+#[cfg(unix)]
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+use {
+    crate::{
+        config::{self, Config},
+        instance::InstanceLock,
+        macors::{self, mouse_offset, play_events_with_progress},
+        storage::{self, list_macros, load_macro},
+    },
+    anyhow::{Context, Error, bail},
+    serde::{Deserialize, Serialize},
+    std::path::PathBuf,
+};
+
+pub fn socket_path() -> PathBuf {
+    config::macors_path().join("macors.sock")
+}
+
+/// A single line sent to `macors serve`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// list all recorded macros
+    List,
+    /// play a macro, streaming progress until it finishes or is stopped
+    Run {
+        name: String,
+        #[serde(default = "default_repeat")]
+        repeat: usize,
+        /// play relative to the current mouse position, like `run-offset`
+        #[serde(default)]
+        offset: bool,
+    },
+    /// stop the running playback, or save and end the running recording
+    Stop,
+    /// what the server is currently doing
+    Status,
+    /// record a new macro until the stop keystrokes are pressed or a stop is requested
+    Record {
+        name: String,
+        #[serde(default)]
+        description: String,
+        #[serde(default)]
+        overwrite: bool,
+    },
+}
+
+fn default_repeat() -> usize {
+    1
+}
+
+/// A line sent back by `macors serve`. Every request is answered by any number of lines
+/// ending with either `done` or `error`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Response {
+    Macros {
+        macros: Vec<MacroInfo>,
+    },
+    Status {
+        #[serde(flatten)]
+        state: State,
+    },
+    Started {
+        name: String,
+        repeat: usize,
+    },
+    Progress {
+        run: usize,
+        index: usize,
+        total: usize,
+    },
+    Aborted {
+        reason: String,
+    },
+    Recording {
+        name: String,
+    },
+    Saved {
+        name: String,
+    },
+    Discarded {
+        name: String,
+    },
+    Stopping,
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MacroInfo {
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum State {
+    Idle,
+    Playing { name: String },
+    Recording { name: String },
+}
+
+/// Listen on the control socket and answer JSON-lines requests until interrupted
+#[cfg(unix)]
+pub fn serve(cfg: Config) -> Result<(), Error> {
+    let path = socket_path();
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            bail!("macors is already serving on {}", path.display());
+        }
+        // left behind by a server that didn't shut down cleanly
+        std::fs::remove_file(&path)?;
+    }
+    let listener =
+        UnixListener::bind(&path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    println!("listening on {}", path.display());
+
+    let cfg = Arc::new(cfg);
+    let state = Arc::new(Mutex::new(State::Idle));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let cfg = cfg.clone();
+        let state = state.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&cfg, &state, stream) {
+                eprintln!("connection closed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(_cfg: Config) -> Result<(), Error> {
+    bail!("macors serve needs unix domain sockets, which this platform doesn't have")
+}
+
+#[cfg(unix)]
+fn handle_connection(cfg: &Config, state: &Mutex<State>, stream: UnixStream) -> Result<(), Error> {
+    let mut writer = stream.try_clone()?;
+    let mut send = |resp: Response| -> std::io::Result<()> {
+        let mut line = serde_json::to_string(&resp).expect("Failed to serialize response");
+        line.push('\n');
+        writer.write_all(line.as_bytes())
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let req: Request = match serde_json::from_str(&line) {
+            Ok(req) => req,
+            Err(e) => {
+                send(Response::Error {
+                    message: format!("invalid request: {e}"),
+                })?;
+                continue;
+            }
+        };
+        match handle_request(cfg, state, req, &mut send) {
+            Ok(()) => send(Response::Done)?,
+            Err(message) => send(Response::Error { message })?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn handle_request(
+    cfg: &Config,
+    state: &Mutex<State>,
+    req: Request,
+    send: &mut dyn FnMut(Response) -> std::io::Result<()>,
+) -> Result<(), String> {
+    let send_err = |e: std::io::Error| e.to_string();
+    match req {
+        Request::List => {
+            let macros = list_macros()?
+                .into_iter()
+                .map(|(name, description)| MacroInfo { name, description })
+                .collect();
+            send(Response::Macros { macros }).map_err(send_err)
+        }
+        Request::Status => {
+            let state = state.lock().expect("state poisoned").clone();
+            send(Response::Status { state }).map_err(send_err)
+        }
+        Request::Stop => {
+            if *state.lock().expect("state poisoned") == State::Idle {
+                return Err("nothing is running".to_string());
+            }
+            macors::request_stop();
+            send(Response::Stopping).map_err(send_err)
+        }
+        Request::Run {
+            name,
+            repeat,
+            offset,
+        } => {
+            let mcro = load_macro(&name)?;
+            let lock = claim(state, State::Playing { name: name.clone() })?;
+            let res = (|| {
+                send(Response::Started {
+                    name: name.clone(),
+                    repeat,
+                })?;
+                for run in 0..repeat {
+                    let (dx, dy) = if offset {
+                        mouse_offset(&mcro.events)
+                    } else {
+                        (0.0, 0.0)
+                    };
+                    // keep playing even if the client went away, it can still stop us
+                    let mut progress = |index, total| {
+                        let _ = send(Response::Progress { run, index, total });
+                    };
+                    if let Err(abort) =
+                        play_events_with_progress(cfg, &mcro.events, dx, dy, &mut progress)
+                    {
+                        send(Response::Aborted {
+                            reason: abort.to_string(),
+                        })?;
+                        break;
+                    }
+                }
+                Ok(())
+            })();
+            // let go of the lock file before anyone else can claim
+            drop(lock);
+            *state.lock().expect("state poisoned") = State::Idle;
+            res.map_err(send_err)
+        }
+        Request::Record {
+            name,
+            description,
+            overwrite,
+        } => {
+            if !overwrite && storage::exists(&name) {
+                return Err(format!(
+                    "macro \"{name}\" already exists, use overwrite to overwrite"
+                ));
+            }
+            let lock = claim(state, State::Recording { name: name.clone() })?;
+            let res = send(Response::Recording { name: name.clone() })
+                .map(|()| macors::record(cfg, name.clone(), description, None));
+            // let go of the lock file before anyone else can claim
+            drop(lock);
+            *state.lock().expect("state poisoned") = State::Idle;
+            res.and_then(|saved| {
+                if saved {
+                    send(Response::Saved { name })
+                } else {
+                    send(Response::Discarded { name })
+                }
+            })
+            .map_err(send_err)
+        }
+    }
+}
+
+// only one playback or recording at a time, they would fight over the input devices
+#[cfg(unix)]
+fn claim(state: &Mutex<State>, new: State) -> Result<InstanceLock, String> {
+    let mut state = state.lock().expect("state poisoned");
+    let command = match (&*state, &new) {
+        (State::Playing { name }, _) => return Err(format!("busy playing {name}")),
+        (State::Recording { name }, _) => return Err(format!("busy recording {name}")),
+        (State::Idle, State::Playing { name }) => format!("serve: run {name}"),
+        (State::Idle, State::Recording { name }) => format!("serve: record {name}"),
+        (State::Idle, State::Idle) => "serve".to_string(),
+    };
+    let lock = InstanceLock::acquire(&command, false).map_err(|e| e.to_string())?;
+    *state = new;
+    Ok(lock)
+}
+
+/// Send a single request to `macors serve` and print every response line until it is done
+#[cfg(unix)]
+pub fn client(request: &str) -> Result<(), Error> {
+    // validate locally for a friendlier error
+    let req: Request = serde_json::from_str(request).context("Invalid request")?;
+    let path = socket_path();
+    let mut stream = UnixStream::connect(&path).with_context(|| {
+        format!(
+            "Failed to connect to {}, is `macors serve` running?",
+            path.display()
+        )
+    })?;
+    let mut line = serde_json::to_string(&req)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        println!("{line}");
+        match serde_json::from_str::<Response>(&line) {
+            Ok(Response::Done) => return Ok(()),
+            Ok(Response::Error { message }) => bail!(message),
+            _ => {}
+        }
+    }
+    bail!("server closed the connection")
+}
+
+#[cfg(not(unix))]
+pub fn client(_request: &str) -> Result<(), Error> {
+    bail!("macors client needs unix domain sockets, which this platform doesn't have")
+}
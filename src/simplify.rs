@@ -0,0 +1,90 @@
+use crate::macors::{Event, MouseEventMove};
+
+/// Simplify every run of mouse moves with Ramer-Douglas-Peucker, dropping the moves which are
+/// within `tolerance` pixels of the simplified path. The waits of dropped moves are merged into
+/// the next kept one, so the macro takes as long as before.
+pub fn simplify(events: &[Event], tolerance: f64) -> Vec<Event> {
+    let mut out = Vec::with_capacity(events.len());
+    let mut i = 0;
+    while i < events.len() {
+        // a run is any stretch of moves and waits, up to its last move
+        let end = events[i..]
+            .iter()
+            .position(|e| !matches!(e, Event::Wait(_) | Event::MouseMove(_)))
+            .map_or(events.len(), |n| i + n);
+        let Some(last) = events[i..end]
+            .iter()
+            .rposition(|e| matches!(e, Event::MouseMove(_)))
+        else {
+            let end = end.max(i + 1);
+            out.extend_from_slice(&events[i..end]);
+            i = end;
+            continue;
+        };
+        simplify_run(&events[i..=i + last], tolerance, &mut out);
+        i += last + 1;
+    }
+    out
+}
+
+fn simplify_run(run: &[Event], tolerance: f64, out: &mut Vec<Event>) {
+    // each move with the waits leading up to it
+    let mut steps: Vec<(Option<u64>, &MouseEventMove)> = Vec::new();
+    let mut wait = None;
+    for ev in run {
+        match ev {
+            Event::Wait(ms) => wait = Some(wait.unwrap_or(0) + ms),
+            Event::MouseMove(m) => steps.push((wait.take(), m)),
+            _ => unreachable!("runs only hold moves and waits"),
+        }
+    }
+
+    let points: Vec<(f64, f64)> = steps.iter().map(|(_, m)| (m.x, m.y)).collect();
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+    rdp(&points, tolerance, &mut keep);
+
+    let mut wait = None;
+    for ((ms, m), keep) in steps.into_iter().zip(keep) {
+        if let Some(ms) = ms {
+            wait = Some(wait.unwrap_or(0) + ms);
+        }
+        if keep {
+            if let Some(ms) = wait.take() {
+                out.push(Event::Wait(ms));
+            }
+            out.push(Event::MouseMove(m.clone()));
+        }
+    }
+}
+
+// mark the points to keep between the (kept) first and last point
+fn rdp(points: &[(f64, f64)], tolerance: f64, keep: &mut [bool]) {
+    if points.len() < 3 {
+        return;
+    }
+    let (first, last) = (points[0], points[points.len() - 1]);
+    let (index, dist) = points[1..points.len() - 1]
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (i + 1, distance_to_segment(*p, first, last)))
+        .fold((0, -1.0), |max, d| if d.1 > max.1 { d } else { max });
+    if dist > tolerance {
+        keep[index] = true;
+        rdp(&points[..=index], tolerance, &mut keep[..=index]);
+        rdp(&points[index..], tolerance, &mut keep[index..]);
+    }
+}
+
+fn distance_to_segment(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len_sq = dx * dx + dy * dy;
+    let t = if len_sq == 0.0 {
+        0.0
+    } else {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len_sq).clamp(0.0, 1.0)
+    };
+    let (cx, cy) = (a.0 + t * dx, a.1 + t * dy);
+    ((p.0 - cx).powi(2) + (p.1 - cy).powi(2)).sqrt()
+}
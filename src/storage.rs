@@ -0,0 +1,367 @@
+use {
+    crate::{
+        binary, config, document,
+        macors::Macro,
+        migrate::{self, CURRENT_VERSION},
+        script,
+    },
+    schemars::JsonSchema,
+    serde::{Deserialize, Serialize, de::DeserializeOwned},
+    std::{
+        collections::BTreeSet,
+        fs::{self, File},
+        io::BufReader,
+        path::{Path, PathBuf},
+    },
+};
+
+/// The file formats a macro can be stored in, picked by the file extension
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Toml,
+    Json,
+    /// `.mcr` scripts, the easiest to write by hand
+    #[serde(alias = "mcr")]
+    #[value(alias = "mcr")]
+    Script,
+    /// compact and quick to load, for very large recordings
+    Binary,
+}
+
+impl Format {
+    /// Every format, in the order they are looked for when finding a macro by name
+    pub const ALL: [Format; 4] = [Format::Toml, Format::Json, Format::Script, Format::Binary];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Toml => "toml",
+            Format::Json => "json",
+            Format::Script => "mcr",
+            Format::Binary => "macb",
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let ext = path.extension()?;
+        Format::ALL.into_iter().find(|f| ext == f.extension())
+    }
+
+    /// Whether files of this format can be read and edited as text
+    pub fn is_text(self) -> bool {
+        self != Format::Binary
+    }
+
+    pub fn serialize(self, mcro: &Macro) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Toml => to_toml(mcro).map(String::into_bytes),
+            Format::Json => {
+                let mut json_string = serde_json::to_string_pretty(mcro)
+                    .map_err(|e| format!("Failed to serialize macro: {e}"))?;
+                json_string.push('\n');
+                Ok(json_string.into_bytes())
+            }
+            Format::Script => Ok(script::print(mcro).into_bytes()),
+            Format::Binary => binary::encode(mcro),
+        }
+    }
+
+    /// Deserialize a macro, migrating it on the way if it was written by an older macors
+    pub fn deserialize(self, contents: &[u8]) -> Result<Macro, String> {
+        let decoded = match self {
+            Format::Binary => binary::decode(contents)?,
+            Format::Script => self.script(contents)?,
+            _ => return self.deserialize_text(contents),
+        };
+        if decoded.version == CURRENT_VERSION {
+            return Ok(decoded);
+        }
+        // read straight into `Macro`, the migrations still go over it as a document like any
+        // other format's
+        let mut doc = serde_json::to_value(&decoded)
+            .map_err(|e| format!("Failed to deserialize macro file: {e}"))?;
+        migrate::upgrade(&mut doc, decoded.version)?;
+        serde_json::from_value(doc).map_err(|e| format!("Failed to deserialize macro file: {e}"))
+    }
+
+    fn deserialize_text(self, contents: &[u8]) -> Result<Macro, String> {
+        let version = self.version(contents)?;
+        migrate::check(version)?;
+        if version == CURRENT_VERSION {
+            return self.parse(contents);
+        }
+        let mut doc: serde_json::Value = self.parse(contents)?;
+        migrate::upgrade(&mut doc, version)?;
+        serde_json::from_value(doc).map_err(|e| format!("Failed to deserialize macro file: {e}"))
+    }
+
+    /// The format version the macro was written in
+    pub fn version(self, contents: &[u8]) -> Result<u32, String> {
+        match self {
+            Format::Binary => binary::Reader::new(contents).map(|r| r.header().version),
+            Format::Script => self.script(contents).map(|mcro| mcro.version),
+            _ => self.parse::<Versioned>(contents).map(|v| v.version),
+        }
+    }
+
+    fn script(self, contents: &[u8]) -> Result<Macro, String> {
+        std::str::from_utf8(contents)
+            .map_err(|e| e.to_string())
+            .and_then(script::parse)
+            .map_err(|e| format!("Failed to deserialize macro file: {e}"))
+    }
+
+    fn parse<T: DeserializeOwned>(self, contents: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Toml => std::str::from_utf8(contents)
+                .map_err(|e| e.to_string())
+                .and_then(|s| toml::from_str(s).map_err(|e| e.to_string())),
+            Format::Json => serde_json::from_slice(contents).map_err(|e| e.to_string()),
+            Format::Script => Err("scripts are parsed by hand".to_string()),
+            Format::Binary => Err("binary macros aren't text".to_string()),
+        }
+        .map_err(|e| format!("Failed to deserialize macro file: {e}"))
+    }
+
+    /// The 1-based line on which the event with the given index starts, for pointing an
+    /// editor at it
+    pub fn event_line(self, contents: &str, index: usize) -> Option<usize> {
+        match self {
+            Format::Toml => contents
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| line.trim() == "[[events]]")
+                .nth(index)
+                .map(|(line_no, _)| line_no + 1),
+            Format::Json => json_event_line(contents, index),
+            Format::Script => script::event_line(contents, index),
+            Format::Binary => None,
+        }
+    }
+}
+
+// just the version of a macro file, missing from those written before macros were versioned
+#[derive(Deserialize)]
+struct Versioned {
+    #[serde(default)]
+    version: u32,
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Format::Toml => "toml",
+            Format::Json => "json",
+            Format::Script => "script",
+            Format::Binary => "binary",
+        };
+        f.write_str(name)
+    }
+}
+
+// walk the json by hand since serde_json doesn't keep positions, every event is an object
+// directly inside the top level "events" array
+fn json_event_line(contents: &str, index: usize) -> Option<usize> {
+    let mut line_no = 1;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut string = String::new();
+    let mut last_key = String::new();
+    let mut in_events = false;
+    let mut seen = 0usize;
+    for c in contents.chars() {
+        if c == '\n' {
+            line_no += 1;
+        }
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+                if depth == 1 {
+                    last_key = std::mem::take(&mut string);
+                }
+            } else {
+                string.push(c);
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+                string.clear();
+            }
+            '{' | '[' => {
+                if in_events && depth == 2 && c == '{' {
+                    if seen == index {
+                        return Some(line_no);
+                    }
+                    seen += 1;
+                }
+                if depth == 1 && c == '[' && last_key == "events" {
+                    in_events = true;
+                }
+                depth += 1;
+            }
+            '}' | ']' => {
+                depth = depth.saturating_sub(1);
+                if depth == 1 {
+                    in_events = false;
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The macro as a freshly laid out TOML document
+pub fn to_toml(mcro: &Macro) -> Result<String, String> {
+    let toml_string =
+        toml::to_string(mcro).map_err(|e| format!("Failed to serialize macro: {e}"))?;
+    // manually make the toml string nicer
+    Ok(toml_string.replace("[[events]]\n\n", "[[events]]\n"))
+}
+
+/// The file holding the macro with the given name, and its format
+pub fn find(name: &str) -> Option<(PathBuf, Format)> {
+    Format::ALL
+        .into_iter()
+        .map(|format| (file_path(name, format), format))
+        .find(|(path, _)| path.is_file())
+}
+
+fn file_path(name: &str, format: Format) -> PathBuf {
+    config::macros_path().join(format!("{name}.{}", format.extension()))
+}
+
+pub fn exists(name: &str) -> bool {
+    find(name).is_some()
+}
+
+/// Read and deserialize the macro with the given name, whatever its format
+pub fn load_macro(name: &str) -> Result<Macro, String> {
+    let Some((path, format)) = find(name) else {
+        return Err("Macro not found".to_string());
+    };
+    let contents = fs::read(&path).map_err(|e| format!("Failed to read macro file: {e}"))?;
+    format.deserialize(&contents)
+}
+
+/// The description of the macro with the given name, without reading all of a binary one
+pub fn load_description(name: &str) -> Result<String, String> {
+    match find(name) {
+        Some((path, Format::Binary)) => {
+            let file = File::open(&path).map_err(|e| format!("Failed to read macro file: {e}"))?;
+            let reader = binary::Reader::new(BufReader::new(file))?;
+            Ok(reader.header().description.clone())
+        }
+        _ => load_macro(name).map(|mcro| mcro.description),
+    }
+}
+
+/// The format version the macro with the given name is written in, and its format
+pub fn version_of(name: &str) -> Result<(u32, Format), String> {
+    let Some((path, format)) = find(name) else {
+        return Err("Macro not found".to_string());
+    };
+    let contents = fs::read(&path).map_err(|e| format!("Failed to read macro file: {e}"))?;
+    Ok((format.version(&contents)?, format))
+}
+
+/// Serialize the macro and write it under the given name, replacing any existing one. An
+/// existing macro keeps its format, a new one is written in `default`.
+pub fn write_macro(name: &str, mcro: &Macro, default: Format) -> Result<(), String> {
+    let format = find(name).map_or(default, |(_, format)| format);
+    write_macro_as(name, mcro, format)
+}
+
+/// Write the macro under the given name in exactly this format, removing it in any other. An
+/// existing TOML file is edited in place, keeping its comments and layout.
+pub fn write_macro_as(name: &str, mcro: &Macro, format: Format) -> Result<(), String> {
+    let existing = match format {
+        Format::Toml => fs::read_to_string(file_path(name, format)).ok(),
+        _ => None,
+    };
+    let contents = match existing.map(|existing| document::update(&existing, mcro)) {
+        Some(Ok(updated)) => updated.into_bytes(),
+        // a file which doesn't parse any more is simply replaced
+        Some(Err(_)) | None => format.serialize(mcro)?,
+    };
+    write_file(name, &contents, format)
+}
+
+/// Write a new recording under the given name, in the format of any macro it replaces. Unlike
+/// `write_macro` nothing of the old file is kept, its comments were about other events.
+pub fn replace_macro(name: &str, mcro: &Macro, default: Format) -> Result<(), String> {
+    let format = find(name).map_or(default, |(_, format)| format);
+    write_file(name, &format.serialize(mcro)?, format)
+}
+
+fn write_file(name: &str, contents: &[u8], format: Format) -> Result<(), String> {
+    let macros_dir = config::macros_path();
+    fs::create_dir_all(&macros_dir)
+        .map_err(|e| format!("Failed to create macros directory: {e}"))?;
+    fs::write(file_path(name, format), contents)
+        .map_err(|e| format!("Failed to write macro file: {e}"))?;
+    remove_other_formats(name, format)
+}
+
+/// Copy the macro file as it is to a new name, e.g. for a backup
+pub fn copy_macro(name: &str, to: &str) -> Result<(), String> {
+    let Some((path, format)) = find(name) else {
+        return Err("Macro not found".to_string());
+    };
+    let target = file_path(to, format);
+    fs::copy(&path, &target).map_err(|e| format!("Failed to copy macro file: {e}"))?;
+    remove_other_formats(to, format)
+}
+
+// a macro is only ever kept in one format
+fn remove_other_formats(name: &str, format: Format) -> Result<(), String> {
+    for other in Format::ALL.into_iter().filter(|f| *f != format) {
+        let stale = file_path(name, other);
+        if stale.is_file() {
+            fs::remove_file(&stale)
+                .map_err(|e| format!("Failed to remove {}: {e}", stale.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete the macro with the given name, returns whether there was one
+pub fn remove_macro(name: &str) -> Result<bool, String> {
+    let Some((path, _)) = find(name) else {
+        return Ok(false);
+    };
+    fs::remove_file(&path).map_err(|e| format!("Failed to remove macro file: {e}"))?;
+    Ok(true)
+}
+
+/// Names of all recorded macros, in order
+pub fn macro_names() -> Result<Vec<String>, String> {
+    let entries = fs::read_dir(config::macros_path())
+        .map_err(|e| format!("Failed to read macros directory: {e}"))?;
+    let names: BTreeSet<String> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && Format::from_path(path).is_some())
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+        .collect();
+    Ok(names.into_iter().collect())
+}
+
+/// Names and descriptions of all recorded macros
+pub fn list_macros() -> Result<Vec<(String, String)>, String> {
+    let mut macros = Vec::new();
+    for name in macro_names()? {
+        let description = load_description(&name).map_err(|e| format!("{name}: {e}"))?;
+        macros.push((name, description));
+    }
+    Ok(macros)
+}
@@ -0,0 +1,135 @@
+use {
+    rdevin::{EventType, Key},
+    std::{thread, time::Duration},
+};
+
+// give the target application a moment to keep up with synthesized typing
+const KEY_DELAY_MS: u64 = 5;
+
+/// The key (and whether shift is needed) producing `c` on a US QWERTY layout
+pub fn char_to_key(c: char) -> Option<(Key, bool)> {
+    let lower = c.to_ascii_lowercase();
+    let letter = match lower {
+        'a' => Some(Key::KeyA),
+        'b' => Some(Key::KeyB),
+        'c' => Some(Key::KeyC),
+        'd' => Some(Key::KeyD),
+        'e' => Some(Key::KeyE),
+        'f' => Some(Key::KeyF),
+        'g' => Some(Key::KeyG),
+        'h' => Some(Key::KeyH),
+        'i' => Some(Key::KeyI),
+        'j' => Some(Key::KeyJ),
+        'k' => Some(Key::KeyK),
+        'l' => Some(Key::KeyL),
+        'm' => Some(Key::KeyM),
+        'n' => Some(Key::KeyN),
+        'o' => Some(Key::KeyO),
+        'p' => Some(Key::KeyP),
+        'q' => Some(Key::KeyQ),
+        'r' => Some(Key::KeyR),
+        's' => Some(Key::KeyS),
+        't' => Some(Key::KeyT),
+        'u' => Some(Key::KeyU),
+        'v' => Some(Key::KeyV),
+        'w' => Some(Key::KeyW),
+        'x' => Some(Key::KeyX),
+        'y' => Some(Key::KeyY),
+        'z' => Some(Key::KeyZ),
+        _ => None,
+    };
+    if let Some(key) = letter {
+        return Some((key, c.is_ascii_uppercase()));
+    }
+
+    let key = match c {
+        '1' | '!' => Key::Num1,
+        '2' | '@' => Key::Num2,
+        '3' | '#' => Key::Num3,
+        '4' | '$' => Key::Num4,
+        '5' | '%' => Key::Num5,
+        '6' | '^' => Key::Num6,
+        '7' | '&' => Key::Num7,
+        '8' | '*' => Key::Num8,
+        '9' | '(' => Key::Num9,
+        '0' | ')' => Key::Num0,
+        '-' | '_' => Key::Minus,
+        '=' | '+' => Key::Equal,
+        '[' | '{' => Key::LeftBracket,
+        ']' | '}' => Key::RightBracket,
+        '\\' | '|' => Key::BackSlash,
+        ';' | ':' => Key::SemiColon,
+        '\'' | '"' => Key::Quote,
+        ',' | '<' => Key::Comma,
+        '.' | '>' => Key::Dot,
+        '/' | '?' => Key::Slash,
+        '`' | '~' => Key::BackQuote,
+        ' ' => Key::Space,
+        '\n' => Key::Return,
+        '\t' => Key::Tab,
+        _ => return None,
+    };
+    let shift = "!@#$%^&*()_+{}|:\"<>?~".contains(c);
+    Some((key, shift))
+}
+
+fn send(ev_type: &EventType) {
+    if rdevin::simulate(ev_type).is_err() {
+        eprintln!("Failed to simulate {ev_type:?}");
+    }
+    thread::sleep(Duration::from_millis(KEY_DELAY_MS));
+}
+
+/// Whether the key types a character (or changes which one), as opposed to e.g. Enter or F1
+pub fn is_typing_key(key: Key) -> bool {
+    matches!(key, Key::ShiftLeft | Key::ShiftRight | Key::CapsLock)
+        || (' '..='~').any(|c| char_to_key(c).is_some_and(|(k, _)| k == key))
+}
+
+/// Whether the key only changes which character another key types
+pub fn is_shift_key(key: Key) -> bool {
+    matches!(key, Key::ShiftLeft | Key::ShiftRight | Key::AltGr)
+}
+
+/// Types text by character rather than by physical key, so it comes out the same on any
+/// keyboard layout
+pub struct TextSender(enigo::Enigo);
+
+impl TextSender {
+    pub fn new() -> Result<Self, String> {
+        enigo::Enigo::new(&enigo::Settings::default())
+            .map(TextSender)
+            .map_err(|e| format!("Failed to set up typing by character: {e}"))
+    }
+
+    pub fn send(&mut self, text: &str) {
+        use enigo::Keyboard;
+        if let Err(e) = self.0.text(text) {
+            eprintln!("Failed to type {text:?}: {e}");
+        }
+        thread::sleep(Duration::from_millis(KEY_DELAY_MS));
+    }
+}
+
+/// Press and release a single key
+pub fn tap(key: Key) {
+    send(&EventType::KeyPress(key));
+    send(&EventType::KeyRelease(key));
+}
+
+/// Type out `text` key by key. Characters which can't be typed on a US layout are skipped.
+pub fn type_text(text: &str) {
+    for c in text.chars() {
+        let Some((key, shift)) = char_to_key(c) else {
+            eprintln!("Can't type {c:?}, skipping");
+            continue;
+        };
+        if shift {
+            send(&EventType::KeyPress(Key::ShiftLeft));
+        }
+        tap(key);
+        if shift {
+            send(&EventType::KeyRelease(Key::ShiftLeft));
+        }
+    }
+}